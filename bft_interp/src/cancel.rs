@@ -0,0 +1,50 @@
+//! Stopping a running program from another thread
+//!
+//! A `CancelToken` is shared between the virtual machine and whoever may
+//! want to stop it.  The machine looks at the token every
+//! STEPS_PER_CANCEL_CHECK steps and fails with `VMError::Cancelled` once it
+//! has been cancelled, leaving the program counter on the next instruction.
+//! Cancelling only sets a flag, so it may be done from a signal handler.
+
+use crate::VMError;
+use bft_types::InputInstruction;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How many steps to run between looks at the token
+const STEPS_PER_CANCEL_CHECK: u64 = 1024;
+
+/// A flag which asks the virtual machines holding it to stop
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    /// Ask the machines holding this token, or a clone of it, to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Return whether cancel has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Fail if there is a token, it is time to look at it and it has been
+/// cancelled.  This is called before a step is counted.
+pub(crate) fn check(
+    token: &Option<CancelToken>,
+    steps: u64,
+    instruction: InputInstruction,
+) -> Result<(), VMError> {
+    match token {
+        Some(token) if steps.is_multiple_of(STEPS_PER_CANCEL_CHECK) && token.is_cancelled() => {
+            Err(VMError::Cancelled(instruction.into()))
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,159 @@
+//! Finding loops which will never finish
+//!
+//! Between reads of input the machine is deterministic, so if it is ever
+//! in the same state twice it will go round the same states forever.  The
+//! detector looks at the state each time a `]` jumps back: the program
+//! counter, the head and the cells the head has visited, every other cell
+//! still holds its starting value.  Rather than keep every state it uses
+//! Brent's algorithm, saving one state at each power of two and comparing
+//! the states after it with that one, and only compares the cells when the
+//! program counter and head match.  A `,` which reads a byte starts it over.
+//!
+//! Positions are counted from the origin, so they are not upset by the tape
+//! growing to the left.
+
+use crate::tape::Tape;
+use crate::CellKind;
+use std::collections::VecDeque;
+
+/// The state of the machine at a `]` which jumped back
+#[derive(Debug, Clone)]
+struct SavedState<T> {
+    program_counter: usize,
+    head: isize,
+    /// The visited cells, from the lowest position
+    cells: VecDeque<T>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct LoopDetector<T> {
+    saved: Option<SavedState<T>>,
+    /// How many states to compare with the saved one before saving another
+    power: u64,
+    /// How many states have been compared with the saved one
+    length: u64,
+    /// The lowest and highest positions the head has visited
+    lowest: isize,
+    highest: isize,
+}
+
+impl<T> LoopDetector<T>
+where
+    T: CellKind,
+{
+    /// Create a detector for the machine with this tape
+    pub(crate) fn new(tape: &Tape<T>) -> LoopDetector<T> {
+        let position = head_position(tape);
+        LoopDetector {
+            saved: None,
+            power: 1,
+            length: 0,
+            lowest: position,
+            highest: position,
+        }
+    }
+
+    /// Note that the head has moved.  The cells it has not visited before
+    /// still hold the values they had when the state was saved, so they are
+    /// added to the saved state.
+    pub(crate) fn visit(&mut self, tape: &Tape<T>) {
+        let position = head_position(tape);
+
+        while position < self.lowest {
+            self.lowest -= 1;
+            if let Some(saved) = &mut self.saved {
+                saved.cells.push_front(cell_at(tape, self.lowest));
+            }
+        }
+        while position > self.highest {
+            self.highest += 1;
+            if let Some(saved) = &mut self.saved {
+                saved.cells.push_back(cell_at(tape, self.highest));
+            }
+        }
+    }
+
+    /// Forget the states seen so far, input makes them no use
+    pub(crate) fn reset(&mut self) {
+        self.saved = None;
+        self.power = 1;
+        self.length = 0;
+    }
+
+    /// Look at the state after the `]` at program_counter jumped back,
+    /// returning whether the machine has been in it before
+    pub(crate) fn repeated(&mut self, program_counter: usize, tape: &Tape<T>) -> bool {
+        let head = head_position(tape);
+
+        if let Some(saved) = &self.saved {
+            if saved.program_counter == program_counter
+                && saved.head == head
+                && saved.cells.iter().copied().eq(self.visited_cells(tape))
+            {
+                return true;
+            }
+        }
+
+        if self.saved.is_none() || self.length == self.power {
+            if self.saved.is_some() {
+                self.power *= 2;
+            }
+            self.saved = Some(SavedState {
+                program_counter,
+                head,
+                cells: self.visited_cells(tape).collect(),
+            });
+            self.length = 0;
+        }
+        self.length += 1;
+
+        false
+    }
+
+    fn visited_cells<'t>(&self, tape: &'t Tape<T>) -> impl Iterator<Item = T> + 't {
+        (self.lowest..=self.highest).map(move |position| cell_at(tape, position))
+    }
+}
+
+fn head_position<T: CellKind>(tape: &Tape<T>) -> isize {
+    tape.head() as isize - tape.origin() as isize
+}
+
+fn cell_at<T: CellKind>(tape: &Tape<T>, position: isize) -> T {
+    tape.cell((tape.origin() as isize + position) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoopDetector;
+    use crate::tape::{Tape, TapeBoundary};
+
+    #[test]
+    fn finds_repeated_states() {
+        let mut tape: Tape<u8> = Tape::new(4, TapeBoundary::Error);
+        let mut detector = LoopDetector::new(&tape);
+
+        // A cycle of three states takes a few goes round to find
+        let mut found = None;
+        for round in 0..20 {
+            tape.set_current((round % 3) as u8);
+            if detector.repeated(7, &tape) {
+                found = Some(round);
+                break;
+            }
+        }
+        assert_eq!(found, Some(6));
+
+        detector.reset();
+        tape.move_right().unwrap();
+        detector.visit(&tape);
+        tape.set_current(1);
+        assert!(!detector.repeated(7, &tape));
+        tape.set_current(2);
+        assert!(!detector.repeated(7, &tape));
+        tape.set_current(1);
+        assert!(!detector.repeated(7, &tape));
+        tape.set_current(2);
+        assert!(detector.repeated(7, &tape));
+    }
+}
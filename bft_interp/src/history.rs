@@ -0,0 +1,91 @@
+//! Recording what each instruction changes, so that it can be undone
+//!
+//! When recording is on the virtual machine logs a `Change` for every
+//! instruction which runs without an error.  Undoing a change moves the
+//! program counter and the head back and takes the delta off the cell.
+//! Input read by an undone `,` is kept and read again when the program runs
+//! forward, but output can not be taken back, so a `.` which is undone and
+//! run again writes its byte twice.  Cells added to a growing tape stay on
+//! it.
+
+use std::collections::VecDeque;
+
+/// What a single instruction changed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change {
+    /// The index of the instruction
+    pub program_counter: usize,
+    /// How far the head moved, measured from the origin so that it is not
+    /// upset by the tape growing to the left
+    pub head_delta: isize,
+    /// How much the cell under the head changed by
+    pub cell_delta: i128,
+    /// The byte read by a `,`, None if it read nothing
+    pub input: Option<u8>,
+}
+
+/// The changes made by the most recent instructions
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    changes: VecDeque<Change>,
+    capacity: usize,
+}
+
+impl History {
+    /// Create an empty history which keeps up to capacity changes
+    pub(crate) fn new(capacity: usize) -> History {
+        History {
+            changes: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Record a change, forgetting the oldest one if the history is full
+    pub(crate) fn push(&mut self, change: Change) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.changes.len() == self.capacity {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    /// Take the most recent change
+    pub(crate) fn pop(&mut self) -> Option<Change> {
+        self.changes.pop_back()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Change, History};
+
+    #[test]
+    fn oldest_changes_are_forgotten() {
+        let change = |program_counter| Change {
+            program_counter,
+            head_delta: 0,
+            cell_delta: 1,
+            input: None,
+        };
+        let mut history = History::new(2);
+
+        for program_counter in 0..3 {
+            history.push(change(program_counter));
+        }
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.pop(), Some(change(2)));
+        assert_eq!(history.pop(), Some(change(1)));
+        assert_eq!(history.pop(), None);
+    }
+}
@@ -0,0 +1,405 @@
+//! A virtual machine which runs optimised IR programs
+//!
+//! This runs the output of the `bft_types::passes` optimiser, errors are
+//! reported against the first command of the span the failing operation
+//! was built from.
+//!
+//! A folded run of moves is applied in one go, so if it falls off the tape
+//! the error is reported against the first command of the run and the head
+//! is left where the run started.  Runs are only folded across a change of
+//! direction when the head can not fall off, see
+//! `PassManager::set_skip_cells`, so the same programs fail on both machines.
+
+use crate::cancel::{self, CancelToken};
+use crate::limits::{ResourceLimits, Usage};
+use crate::tape::{Tape, TapeBoundary, TapeError, TapeGrowth};
+use crate::{boundary_for, read_byte, CellKind, DumpWriter, EofBehavior, VMError};
+use bft_types::ir::{IrProgram, Op};
+use bft_types::BFProgram;
+use bft_types::InputInstruction;
+use std::io::Read;
+use std::io::Write;
+
+#[derive(Debug)]
+pub struct IrVirtualMachine<'a, T> {
+    program: &'a BFProgram,
+    ir: &'a IrProgram,
+    program_counter: usize,
+    tape: Tape<T>,
+    eof: EofBehavior,
+    dump: DumpWriter<'a>,
+    limits: ResourceLimits,
+    usage: Usage,
+    cancel: Option<CancelToken>,
+}
+
+impl<'a, T> IrVirtualMachine<'a, T>
+where
+    T: CellKind,
+{
+    /// Create a new IrVirtualMachine to run the IR lowered from the program
+    pub fn new(
+        program: &'a BFProgram,
+        ir: &'a IrProgram,
+        can_grow: bool,
+        tape_size: usize,
+    ) -> IrVirtualMachine<'a, T> {
+        let tape_size = if tape_size == 0 { 30000 } else { tape_size };
+        IrVirtualMachine {
+            program,
+            ir,
+            program_counter: 0,
+            tape: Tape::new(tape_size, boundary_for(can_grow)),
+            eof: EofBehavior::Unchanged,
+            dump: DumpWriter::default(),
+            limits: ResourceLimits::default(),
+            usage: Usage::default(),
+            cancel: None,
+        }
+    }
+
+    /// Change what `Input` stores at the end of the input, the default is to
+    /// leave the cell unchanged
+    pub fn set_eof_behavior(&mut self, eof: EofBehavior) {
+        self.eof = eof;
+    }
+
+    /// Limit how much the program may do, the default is no limits.  Each
+    /// operation counts as one step, and a scan counts one more for every
+    /// cell it moves over.  Fails with `TapeError::LimitExceeded`, changing
+    /// nothing, if the tape is already bigger than max_tape.
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) -> Result<(), TapeError> {
+        self.tape.set_limit(limits.max_tape)?;
+        self.limits = limits;
+        Ok(())
+    }
+
+    /// Stop with `VMError::Cancelled` soon after the token is cancelled, see
+    /// the `cancel` module
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    /// Change where the `#` extension writes its dumps, the default is
+    /// stderr
+    pub fn set_dump_writer(&mut self, writer: impl Write + 'a) {
+        self.dump = DumpWriter(Box::new(writer));
+    }
+
+    /// Change what happens when the head moves off the end of the tape,
+    /// replacing the choice made by can_grow
+    pub fn set_tape_boundary(&mut self, boundary: TapeBoundary) {
+        self.tape.set_boundary(boundary);
+    }
+
+    /// Change how the tape grows when the head runs off the end of it,
+    /// this has no effect unless the tape boundary is Grow
+    pub fn set_tape_growth(&mut self, growth: TapeGrowth) {
+        self.tape.set_growth(growth);
+    }
+
+    /// Return the tape
+    pub fn tape(&self) -> &Tape<T> {
+        &self.tape
+    }
+
+    /// Return the command the current operation came from
+    fn current_instruction(&self) -> InputInstruction {
+        *self.ir.ops()[self.program_counter].source(self.program)
+    }
+
+    /// Turn a failed head movement into the error for the current operation
+    fn tape_error(&self, error: TapeError) -> VMError {
+        match error {
+            TapeError::HeadOutOfBounds => {
+                VMError::InvalidHeadPosition(self.current_instruction().into())
+            }
+            TapeError::TooBig => VMError::TapeTooBig(self.current_instruction().into()),
+            TapeError::LimitExceeded => {
+                VMError::TapeLimitExceeded(self.current_instruction().into())
+            }
+        }
+    }
+
+    /// Move the head by offset cells
+    fn move_head(&mut self, offset: isize) -> Result<(), VMError> {
+        self.tape.move_by(offset).map_err(|e| self.tape_error(e))
+    }
+
+    /// Move the head one cell at a time in direction until it is on a zero
+    /// cell.  Every move counts as a step, so a scan around a wrapping tape
+    /// with no zero cells is stopped by the limits or the cancel token.
+    fn scan(&mut self, direction: isize, instruction: InputInstruction) -> Result<(), VMError> {
+        while !self.tape.current().is_zero() {
+            cancel::check(&self.cancel, self.usage.steps, instruction)?;
+            self.limits.step(&mut self.usage, instruction)?;
+            self.move_head(direction)?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the IR until the program counter moves past the last operation,
+    /// or an error occurs
+    pub fn interpret<R, W>(&mut self, input: R, output: W) -> Result<(), VMError>
+    where
+        R: Read,
+        W: Write,
+    {
+        self.run(input, output).map_err(|e| {
+            e.with_state(
+                self.tape.head(),
+                self.tape.current().to_i128(),
+                self.usage.steps,
+            )
+        })
+    }
+
+    /// Run the IR, the errors only know the instruction until interpret
+    /// fills in the rest
+    fn run<R, W>(&mut self, mut input: R, mut output: W) -> Result<(), VMError>
+    where
+        R: Read,
+        W: Write,
+    {
+        while self.program_counter < self.ir.len() {
+            let cell = self.tape.current();
+            let instruction = self.current_instruction();
+            cancel::check(&self.cancel, self.usage.steps, instruction)?;
+            self.limits.step(&mut self.usage, instruction)?;
+
+            match self.ir.ops()[self.program_counter].op() {
+                Op::Add(n) => self.tape.set_current(add_signed(cell, n.into())),
+                Op::Move(n) => self.move_head(n)?,
+                Op::SetZero => self.tape.set_current(T::default()),
+                Op::MulAdd { offset, factor } => {
+                    // Skip zero cells so a loop which would never have run
+                    // can not fall off the tape
+                    if !cell.is_zero() {
+                        let target = self.tape.index_at(offset).map_err(|e| self.tape_error(e))?;
+                        let product = cell.to_i128() * i128::from(factor);
+                        let value = add_signed(self.tape.cell(target), product);
+                        self.tape.set_cell(target, value);
+                    }
+                }
+                Op::ScanLeft => self.scan(-1, instruction)?,
+                Op::ScanRight => self.scan(1, instruction)?,
+                Op::Output => {
+                    let bytes = cell.to_output_bytes();
+                    self.limits
+                        .output(&mut self.usage, bytes.len(), instruction)?;
+                    if output.write_all(&bytes).is_err() {
+                        return Err(VMError::IOWriteError(self.current_instruction().into()));
+                    }
+                }
+                Op::Input => match read_byte(&mut input) {
+                    Ok(Some(byte)) => self.tape.set_current(T::from_input_byte(byte)),
+                    Ok(None) => match self.eof {
+                        EofBehavior::Unchanged => (),
+                        EofBehavior::Zero => self.tape.set_current(T::default()),
+                        EofBehavior::MinusOne => self.tape.set_current(T::all_ones()),
+                    },
+                    Err(_) => return Err(VMError::IOReadError(self.current_instruction().into())),
+                },
+                Op::DebugDump => {
+                    // The program counter in the dump is the command's, so
+                    // it matches the plain machine's dump
+                    let span = self.ir.ops()[self.program_counter].span();
+                    self.dump
+                        .dump(self.current_instruction(), span.start, &self.tape)?;
+                }
+                Op::LoopStart { end } => {
+                    if cell.is_zero() {
+                        self.program_counter = end;
+                    }
+                }
+                Op::LoopEnd { start } => {
+                    if !cell.is_zero() {
+                        self.program_counter = start;
+                    }
+                }
+            }
+
+            self.program_counter += 1;
+        }
+
+        Ok(())
+    }
+}
+
+/// Add a signed amount to a cell, wrapping around at the ends of the cell.
+/// Only the low 64 bits of the amount matter as no cell is wider than that
+fn add_signed<T: CellKind>(cell: T, amount: i128) -> T {
+    if amount >= 0 {
+        cell.add(amount as u64).0
+    } else {
+        cell.sub(amount.unsigned_abs() as u64).0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IrVirtualMachine;
+    use crate::limits::ResourceLimits;
+    use crate::tape::TapeBoundary;
+    use crate::BFVirtualMachine;
+    use bft_types::ir::IrProgram;
+    use bft_types::passes::PassManager;
+    use bft_types::BFProgram;
+    use bft_types::Extensions;
+    use std::env;
+    use std::io::Cursor;
+
+    fn run_optimised(program: &BFProgram, opt_level: u32) -> (Vec<u8>, Vec<u8>) {
+        let ir = PassManager::with_opt_level(opt_level).run(IrProgram::lower(program));
+        let mut virtual_machine: IrVirtualMachine<u8> =
+            IrVirtualMachine::new(program, &ir, false, 100);
+        let mut output = Vec::new();
+
+        virtual_machine
+            .interpret(Cursor::new(vec![]), &mut output)
+            .unwrap();
+
+        (virtual_machine.tape().cells().to_vec(), output)
+    }
+
+    #[test]
+    fn hello_world_runs_optimised() {
+        let mut path = env::current_dir().unwrap();
+
+        path.set_file_name("helloworld.bf");
+
+        let program = BFProgram::new(path);
+
+        for opt_level in 0..=PassManager::MAX_OPT_LEVEL {
+            let (_, output) = run_optimised(&program, opt_level);
+            assert_eq!(output, b"Hello World!\n");
+        }
+    }
+
+    #[test]
+    fn optimised_tape_matches_plain_vm() {
+        let source = "[initial comment.]++++++[->+++>++<<]>[-]>>+[<]+++[>+<-]";
+        let program = BFProgram::from_str("compare.bf", source).unwrap();
+
+        let mut plain: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 100);
+        plain.interpret(Cursor::new(vec![]), Vec::new()).unwrap();
+
+        let (tape, _) = run_optimised(&program, PassManager::MAX_OPT_LEVEL);
+        assert_eq!(tape[..10], plain.tape.cells()[..10]);
+    }
+
+    #[test]
+    fn falling_off_the_tape_is_an_error() {
+        let program = BFProgram::from_str("left.bf", "+\n<").unwrap();
+        let ir = IrProgram::lower(&program);
+        let mut virtual_machine: IrVirtualMachine<u8> =
+            IrVirtualMachine::new(&program, &ir, false, 100);
+
+        match virtual_machine.interpret(Cursor::new(vec![]), Vec::new()) {
+            Err(crate::VMError::InvalidHeadPosition(context)) => {
+                assert_eq!(context.instruction.line_number(), 1);
+                assert_eq!(context.head, 0);
+                assert_eq!(context.cell, 1);
+                assert_eq!(context.steps, 2);
+            }
+            other => panic!("Expected a head position error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_match_plain_vm() {
+        let program = BFProgram::from_str("off.bf", "<>+.").unwrap();
+
+        let mut plain: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 10);
+        let plain_error = plain.interpret(Cursor::new(vec![]), Vec::new());
+
+        let ir =
+            PassManager::with_opt_level(PassManager::MAX_OPT_LEVEL).run(IrProgram::lower(&program));
+        let mut virtual_machine: IrVirtualMachine<u8> =
+            IrVirtualMachine::new(&program, &ir, false, 10);
+        let mut output = Vec::new();
+        let error = virtual_machine.interpret(Cursor::new(vec![]), &mut output);
+
+        assert!(matches!(
+            plain_error,
+            Err(crate::VMError::InvalidHeadPosition(_))
+        ));
+        assert_eq!(error, plain_error);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn wide_cells_match_plain_vm() {
+        let source = "++++++++++++++++[>++++++++++++++++<-]>[->++<]<-";
+        let program = BFProgram::from_str("wide.bf", source).unwrap();
+
+        let mut plain: BFVirtualMachine<u16> = BFVirtualMachine::new(&program, false, 10);
+        plain.interpret(Cursor::new(vec![]), Vec::new()).unwrap();
+
+        let ir =
+            PassManager::with_opt_level(PassManager::MAX_OPT_LEVEL).run(IrProgram::lower(&program));
+        let mut virtual_machine: IrVirtualMachine<u16> =
+            IrVirtualMachine::new(&program, &ir, false, 10);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        assert_eq!(virtual_machine.tape().cells(), plain.tape.cells());
+        assert_eq!(plain.tape.cells()[..3], [u16::MAX, 0, 512]);
+    }
+
+    #[test]
+    fn debug_dumps_match_plain_vm() {
+        let extensions = Extensions { debug_dump: true };
+        let program = BFProgram::from_str_with("dump.bf", "++>+++#[-]<\n#", extensions).unwrap();
+
+        let mut plain_dump = Vec::new();
+        {
+            let mut plain: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+            plain.set_dump_writer(&mut plain_dump);
+            plain.interpret(Cursor::new(vec![]), Vec::new()).unwrap();
+        }
+
+        let mut dump = Vec::new();
+        {
+            let ir = PassManager::with_opt_level(PassManager::MAX_OPT_LEVEL)
+                .run(IrProgram::lower(&program));
+            let mut virtual_machine: IrVirtualMachine<u8> =
+                IrVirtualMachine::new(&program, &ir, false, 4);
+            virtual_machine.set_dump_writer(&mut dump);
+            virtual_machine
+                .interpret(Cursor::new(vec![]), Vec::new())
+                .unwrap();
+        }
+
+        let expected = "[Line 0, Col 6] # pc 6, head 1: #0=2 [#1=3] #2=0 #3=0 \n\
+                        [Line 1, Col 0] # pc 11, head 0: [#0=2] #1=0 #2=0 #3=0 \n";
+        assert_eq!(String::from_utf8(plain_dump).unwrap(), expected);
+        assert_eq!(String::from_utf8(dump).unwrap(), expected);
+    }
+
+    #[test]
+    fn scans_are_stopped_by_the_step_limit() {
+        let program = BFProgram::from_str("scan.bf", "+>+>+>+[>]").unwrap();
+        let ir =
+            PassManager::with_opt_level(PassManager::MAX_OPT_LEVEL).run(IrProgram::lower(&program));
+        let mut virtual_machine: IrVirtualMachine<u8> =
+            IrVirtualMachine::new(&program, &ir, false, 4);
+        virtual_machine.set_tape_boundary(TapeBoundary::Wrap);
+        virtual_machine
+            .set_resource_limits(ResourceLimits {
+                max_steps: Some(100),
+                ..ResourceLimits::default()
+            })
+            .unwrap();
+
+        match virtual_machine.interpret(Cursor::new(vec![]), Vec::new()) {
+            Err(crate::VMError::StepLimitExceeded(context)) => {
+                assert_eq!(context.instruction.column_number(), 7);
+                assert_eq!(context.steps, 100);
+            }
+            other => panic!("Expected a step limit error, got {:?}", other),
+        }
+    }
+}
@@ -6,93 +6,437 @@
 //! ========
 //! The eight language commands each consist of a single character:
 //!
-//! Character	Meaning
-//!     >	    increment the data pointer (to point to the next cell to the right).
-//!     <	    decrement the data pointer (to point to the next cell to the left).
-//!     +	    increment (increase by one) the byte at the data pointer.
-//!     -	    decrement (decrease by one) the byte at the data pointer.
-//!     .	    output the byte at the data pointer.
-//!     ,	    accept one byte of input, storing its value in the byte at the data pointer.
-//!     [	    if the byte at the data pointer is zero, then instead of moving the instruction pointer forward to the next command, jump it forward to the command after the matching ] command.
-//!     ]	    if the byte at the data pointer is nonzero, then instead of moving the instruction pointer forward to the next command, jump it back to the command after the matching [ command.
+//! Character    Meaning
+//!     >        increment the data pointer (to point to the next cell to the right).
+//!     <        decrement the data pointer (to point to the next cell to the left).
+//!     +        increment (increase by one) the byte at the data pointer.
+//!     -        decrement (decrease by one) the byte at the data pointer.
+//!     .        output the byte at the data pointer.
+//!     ,        accept one byte of input, storing its value in the byte at the data pointer.
+//!     [        if the byte at the data pointer is zero, then instead of moving the instruction pointer forward to the next command, jump it forward to the command after the matching ] command.
+//!     ]        if the byte at the data pointer is nonzero, then instead of moving the instruction pointer forward to the next command, jump it back to the command after the matching [ command.
 //!             (Alternatively, the ] command may instead be translated as an unconditional jump to the corresponding [ command, or vice versa; programs will behave the same but will run more slowly, due to unnecessary double searching.)
 
+pub mod cancel;
+pub mod cycle;
+pub mod history;
+pub mod ir;
+pub mod limits;
+pub mod observer;
+pub mod profile;
+pub mod snapshot;
+pub mod tape;
+pub mod trace;
+pub mod watch;
+
 use bft_types::BFCommand;
 use bft_types::BFProgram;
 use bft_types::InputInstruction;
+use cancel::CancelToken;
+use cycle::LoopDetector;
+use history::{Change, History};
+use limits::{ResourceLimits, Usage};
+use observer::{Observers, VmObserver};
+use snapshot::{SnapshotError, VmState};
+use std::collections::VecDeque;
 use std::fmt;
 use std::io::Read;
 use std::io::Write;
+use std::ops::Range;
 use std::result::Result;
-use std::vec::Vec;
+use tape::{Tape, TapeBoundary, TapeError, TapeGrowth};
+use trace::{TraceRecord, TraceWriter};
+use watch::{WatchCondition, Watchpoint};
 
-/// This trait is for wrapping the u8 value
-/// This trait covers add and subtract
+/// This trait is for the values held in the tape cells
+/// It covers testing for zero, add and subtract, and converting to and from
+/// the bytes read and written by `,` and `.`
 ///
 /// ========================================
 ///
-pub trait CellKind {
-    fn wrapping_increment(&mut self, number_to_add: u8) -> u8;
+pub trait CellKind:
+    Copy + Default + PartialEq + fmt::Debug + fmt::Display + std::str::FromStr
+{
+    /// The name of the cell type, such as "u8"
+    const NAME: &'static str;
+
+    /// The smallest value a cell can hold
+    const MIN: Self;
+
+    /// The largest value a cell can hold
+    const MAX: Self;
+
+    /// The number of bits in a cell
+    const BITS: u32;
+
+    fn is_zero(&self) -> bool;
+
+    /// Add delta, returning the wrapped result and whether it overflowed
+    fn add(self, delta: u64) -> (Self, bool);
 
-    fn wrapping_decrement(&mut self, number_to_sub: u8) -> u8;
+    /// Subtract delta, returning the wrapped result and whether it overflowed
+    fn sub(self, delta: u64) -> (Self, bool);
+
+    /// The bytes written by `.`, which is the low byte of the cell
+    fn to_output_bytes(self) -> Vec<u8>;
+
+    /// The value stored by `,` for a byte of input
+    fn from_input_byte(byte: u8) -> Self;
+
+    /// Returns the value with every bit set, which is -1 for signed cells
+    fn all_ones() -> Self;
+
+    /// Returns the value as an i128, which can hold every cell type
+    fn to_i128(self) -> i128;
+
+    /// Returns the low bits of value as a cell, the opposite of to_i128
+    fn from_i128(value: i128) -> Self;
+
+    fn wrapping_increment(&mut self, number_to_add: u8) -> Self {
+        self.add(number_to_add.into()).0
+    }
+
+    fn wrapping_decrement(&mut self, number_to_sub: u8) -> Self {
+        self.sub(number_to_sub.into()).0
+    }
+
+    fn saturating_increment(&mut self, number_to_add: u8) -> Self {
+        match self.add(number_to_add.into()) {
+            (_, true) => Self::MAX,
+            (value, false) => value,
+        }
+    }
+
+    fn saturating_decrement(&mut self, number_to_sub: u8) -> Self {
+        match self.sub(number_to_sub.into()) {
+            (_, true) => Self::MIN,
+            (value, false) => value,
+        }
+    }
+
+    /// Returns None if the result does not fit in the cell
+    fn checked_increment(&mut self, number_to_add: u8) -> Option<Self> {
+        match self.add(number_to_add.into()) {
+            (_, true) => None,
+            (value, false) => Some(value),
+        }
+    }
+
+    /// Returns None if the result does not fit in the cell
+    fn checked_decrement(&mut self, number_to_sub: u8) -> Option<Self> {
+        match self.sub(number_to_sub.into()) {
+            (_, true) => None,
+            (value, false) => Some(value),
+        }
+    }
 }
 
-/// Implementation for the CellKind Trait
-/// number_to_add: This is the number to add to the U8
-///
-/// Return: This is old number plus number_to_add, which may be wrapped, if it is more that 255
-impl CellKind for u8 {
-    fn wrapping_increment(&mut self, number_to_add: u8) -> u8 {
-        let current_number = *self;
-        if let Some(n) = self.checked_add(number_to_add) {
-            n
-        } else {
-            (number_to_add - ((u8::max_value() - current_number) + 1)) // + 1 to take into account the zero
+/// Implementation for the CellKind Trait for the integer types
+/// The sums are done in an i128, which can hold any of the cell values plus
+/// or minus any delta, and then truncated which gives the wrapped value
+macro_rules! impl_cell_kind {
+    ($($cell:ty),*) => {
+        $(
+            impl CellKind for $cell {
+                const NAME: &'static str = stringify!($cell);
+
+                const MIN: $cell = <$cell>::MIN;
+
+                const MAX: $cell = <$cell>::MAX;
+
+                const BITS: u32 = <$cell>::BITS;
+
+                fn is_zero(&self) -> bool {
+                    *self == 0
+                }
+
+                fn add(self, delta: u64) -> ($cell, bool) {
+                    let sum = self as i128 + delta as i128;
+                    (sum as $cell, sum > <$cell>::MAX as i128)
+                }
+
+                fn sub(self, delta: u64) -> ($cell, bool) {
+                    let difference = self as i128 - delta as i128;
+                    (difference as $cell, difference < <$cell>::MIN as i128)
+                }
+
+                fn to_output_bytes(self) -> Vec<u8> {
+                    vec![self as u8]
+                }
+
+                fn from_input_byte(byte: u8) -> $cell {
+                    byte as $cell
+                }
+
+                fn all_ones() -> $cell {
+                    !0
+                }
+
+                fn to_i128(self) -> i128 {
+                    self as i128
+                }
+
+                fn from_i128(value: i128) -> $cell {
+                    value as $cell
+                }
+            }
+        )*
+    };
+}
+
+impl_cell_kind!(u8, u16, u32, u64, i8, i32);
+
+/// What to do when adding to or subtracting from a cell overflows
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// Wrap around, so 255 + 1 is 0 for a u8 cell
+    #[default]
+    Wrap,
+    /// Stop at the largest or smallest value
+    Saturate,
+    /// Stop with a `VMError::CellOverflow`
+    Trap,
+}
+
+/// What `,` stores in the cell when there is no more input
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum EofBehavior {
+    /// Leave the cell as it is
+    #[default]
+    Unchanged,
+    /// Store zero
+    Zero,
+    /// Store all ones, which is -1 for signed cells
+    MinusOne,
+}
+
+/// Read a single byte, returning None at the end of the input
+pub(crate) fn read_byte(reader: &mut impl Read) -> std::io::Result<Option<u8>> {
+    let mut buffer: [u8; 1] = [0u8; 1];
+
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buffer[0])),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
     }
+}
 
-    fn wrapping_decrement(&mut self, number_to_sub: u8) -> u8 {
-        let current_number = *self;
-        if let Some(n) = self.checked_sub(number_to_sub) {
-            n
-        } else {
-            (u8::max_value() - ((number_to_sub - current_number) - 1)) // - 1 to take into account the zero
+/// Where the machine was when an error happened
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorContext {
+    /// The instruction which failed
+    pub instruction: InputInstruction,
+    /// The index of the cell under the head
+    pub head: usize,
+    /// The value of the cell under the head
+    pub cell: i128,
+    /// The number of steps counted when the error happened
+    pub steps: u64,
+}
+
+/// Errors start out knowing only the instruction, the machine fills in the
+/// rest of the context before returning them
+impl From<InputInstruction> for ErrorContext {
+    fn from(instruction: InputInstruction) -> Self {
+        ErrorContext {
+            instruction,
+            head: 0,
+            cell: 0,
+            steps: 0,
         }
     }
 }
 
-/*#[derive(Debug)]
-pub struct Logger<W: Write> {
-    out: W,
+/// The ways a program can fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VMError {
+    InvalidHeadPosition(ErrorContext),
+    TapeTooBig(ErrorContext),
+    IOReadError(ErrorContext),
+    IOWriteError(ErrorContext),
+    NestImbalance(ErrorContext),
+    ProgramOutOfBounds(ErrorContext),
+    CellOverflow(ErrorContext),
+    StepLimitExceeded(ErrorContext),
+    TimeLimitExceeded(ErrorContext),
+    OutputLimitExceeded(ErrorContext),
+    TapeLimitExceeded(ErrorContext),
+    Cancelled(ErrorContext),
+    InfiniteLoop(ErrorContext),
+    /// The instruction ran but its trace record could not be written
+    TraceWriteError(ErrorContext),
 }
 
-impl<W: Write> Logger<W> {
-    pub fn new(out: W) -> Self {
-        Logger { out }
+impl VMError {
+    /// Return where the machine was when the error happened
+    pub fn context(&self) -> &ErrorContext {
+        match self {
+            VMError::InvalidHeadPosition(context)
+            | VMError::TapeTooBig(context)
+            | VMError::IOReadError(context)
+            | VMError::IOWriteError(context)
+            | VMError::NestImbalance(context)
+            | VMError::ProgramOutOfBounds(context)
+            | VMError::CellOverflow(context)
+            | VMError::StepLimitExceeded(context)
+            | VMError::TimeLimitExceeded(context)
+            | VMError::OutputLimitExceeded(context)
+            | VMError::TapeLimitExceeded(context)
+            | VMError::Cancelled(context)
+            | VMError::InfiniteLoop(context)
+            | VMError::TraceWriteError(context) => context,
+        }
     }
 
-    // Just write the message directly to the given output with a newline.
-    pub fn log(&mut self, message: &str) {
-        self.out.write(message.as_bytes()).unwrap();
-        self.out.write(b"\n").unwrap();
+    /// Return the instruction which failed
+    pub fn instruction(&self) -> InputInstruction {
+        self.context().instruction
     }
 
-    // Not an interesting method, but could be if we added buffering.
-    pub fn flush(&mut self) {
-        self.out.flush().unwrap();
+    /// Replace the head, cell and steps in the context
+    pub(crate) fn with_state(mut self, head: usize, cell: i128, steps: u64) -> VMError {
+        let context = match &mut self {
+            VMError::InvalidHeadPosition(context)
+            | VMError::TapeTooBig(context)
+            | VMError::IOReadError(context)
+            | VMError::IOWriteError(context)
+            | VMError::NestImbalance(context)
+            | VMError::ProgramOutOfBounds(context)
+            | VMError::CellOverflow(context)
+            | VMError::StepLimitExceeded(context)
+            | VMError::TimeLimitExceeded(context)
+            | VMError::OutputLimitExceeded(context)
+            | VMError::TapeLimitExceeded(context)
+            | VMError::Cancelled(context)
+            | VMError::InfiniteLoop(context)
+            | VMError::TraceWriteError(context) => context,
+        };
+        context.head = head;
+        context.cell = cell;
+        context.steps = steps;
+        self
     }
-}*/
+}
 
-/// Error enum
+impl fmt::Display for VMError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            VMError::InvalidHeadPosition(_) => "the head moved off the end of the tape",
+            VMError::TapeTooBig(_) => "the tape grew past its maximum size",
+            VMError::IOReadError(_) => "unable to read input",
+            VMError::IOWriteError(_) => "unable to write output",
+            VMError::NestImbalance(_) => "the bracket has no match",
+            VMError::ProgramOutOfBounds(_) => "the program counter ran off the end of the program",
+            VMError::CellOverflow(_) => "the cell overflowed",
+            VMError::StepLimitExceeded(_) => "the step limit was reached",
+            VMError::TimeLimitExceeded(_) => "the time limit was reached",
+            VMError::OutputLimitExceeded(_) => "the output limit was reached",
+            VMError::TapeLimitExceeded(_) => "the tape grew past its limit",
+            VMError::Cancelled(_) => "cancelled",
+            VMError::InfiniteLoop(_) => "the loop will never finish",
+            VMError::TraceWriteError(_) => "unable to write the trace",
+        };
+        let context = self.context();
+
+        write!(
+            f,
+            "{}: {} (head at #{}, cell {}, after {} steps)",
+            context.instruction, message, context.head, context.cell, context.steps
+        )
+    }
+}
+
+impl std::error::Error for VMError {}
+
+/// What happened when the machine ran a single instruction
 #[derive(Debug, Clone, Copy)]
-pub enum VMError {
-    NoError(InputInstruction),
-    InvalidHeadPosition(InputInstruction),
-    TapeTooBig(InputInstruction),
-    IOReadError(InputInstruction),
-    IOWriteError(InputInstruction),
-    NestImbalance(InputInstruction),
-    ProgramOutOfBounds(InputInstruction),
+pub enum StepOutcome {
+    /// The instruction ran and there are more to run
+    Continued,
+    /// The program counter is past the last instruction, so the program has
+    /// finished
+    Halted,
+    /// The instruction failed, the program counter is left on it.  The one
+    /// exception is `VMError::TraceWriteError`, where the instruction ran and
+    /// the program counter has moved on.
+    Error(VMError),
+    /// The instruction changed a watched cell, the program counter has moved
+    /// on to the next instruction
+    Watchpoint {
+        /// The index of the watched cell
+        index: usize,
+        instruction: InputInstruction,
+    },
+}
+
+/// Why `resume` returned
+#[derive(Debug, Clone)]
+pub enum RunState {
+    /// The next instruction is a `,` and there is no input, feed some in
+    /// or close the input and resume
+    NeedsInput,
+    /// A `.` wrote these bytes, resume to carry on
+    Output(Vec<u8>),
+    /// The program has finished
+    Halted,
+    /// An instruction failed, the program counter is left on it, as for
+    /// `StepOutcome::Error`
+    Error(VMError),
+}
+
+/// The number of cells either side of the head written by a `#` dump
+const DUMP_WINDOW: usize = 8;
+
+/// Where the `#` extension writes its dumps
+pub(crate) struct DumpWriter<'a>(Box<dyn Write + 'a>);
+
+impl<'a> DumpWriter<'a> {
+    /// Write the command's position, the program counter, the head position
+    /// and the cells around the head
+    pub(crate) fn dump<T>(
+        &mut self,
+        instruction: InputInstruction,
+        program_counter: usize,
+        tape: &Tape<T>,
+    ) -> Result<(), VMError>
+    where
+        T: CellKind,
+    {
+        let out = &mut self.0;
+        write!(
+            out,
+            "{} pc {}, head {}: ",
+            instruction,
+            program_counter,
+            tape.head()
+        )
+        .and_then(|()| tape.write_window(out, DUMP_WINDOW))
+        .map_err(|_| VMError::IOWriteError(instruction.into()))
+    }
+}
+
+impl Default for DumpWriter<'_> {
+    fn default() -> Self {
+        DumpWriter(Box::new(std::io::stderr()))
+    }
+}
+
+impl fmt::Debug for DumpWriter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DumpWriter")
+    }
+}
+
+/// The tape boundary used by a machine created with can_grow
+pub(crate) fn boundary_for(can_grow: bool) -> TapeBoundary {
+    if can_grow {
+        TapeBoundary::Grow
+    } else {
+        TapeBoundary::Error
+    }
 }
 
 #[derive(Debug)]
@@ -100,245 +444,706 @@ pub struct BFVirtualMachine<'a, T> {
     program: &'a BFProgram,
     program_counter: usize,
     program_counter_size: usize,
-    can_grow: bool,
-    tape_pointer: usize,
-    tape_size: usize,
-    tape: Vec<T>,
+    tape: Tape<T>,
+    overflow: OverflowPolicy,
+    eof: EofBehavior,
+    watchpoints: Vec<Watchpoint<T>>,
+    dump: DumpWriter<'a>,
+    limits: ResourceLimits,
+    usage: Usage,
+    trace: Option<TraceWriter<'a>>,
+    history: Option<History>,
+    input_buffer: VecDeque<u8>,
+    input_closed: bool,
+    observers: Observers<'a, T>,
+    cancel: Option<CancelToken>,
+    loop_detector: Option<LoopDetector<T>>,
 }
 
 impl<'a, T> BFVirtualMachine<'a, T>
 where
-    T: Default + Clone + Copy + CellKind + std::convert::From<u8>,
-    u8: std::convert::From<T>,
+    T: CellKind,
 {
-    pub fn new(a_program: &BFProgram, can_grow: bool, tape_size: usize) -> BFVirtualMachine<T> {
+    pub fn new(
+        a_program: &'a BFProgram,
+        can_grow: bool,
+        tape_size: usize,
+    ) -> BFVirtualMachine<'a, T> {
         let tape_size = if tape_size == 0 { 30000 } else { tape_size };
-        let tape: Vec<T> = std::iter::repeat(T::default()).take(tape_size).collect();
         BFVirtualMachine {
             program: a_program,
             program_counter: 0,
             program_counter_size: a_program.commands().len(),
-            can_grow,
-            tape_pointer: 0,
-            tape_size,
-            tape,
+            tape: Tape::new(tape_size, boundary_for(can_grow)),
+            overflow: OverflowPolicy::Wrap,
+            eof: EofBehavior::Unchanged,
+            watchpoints: Vec::new(),
+            dump: DumpWriter::default(),
+            limits: ResourceLimits::default(),
+            usage: Usage::default(),
+            trace: None,
+            history: None,
+            input_buffer: VecDeque::new(),
+            input_closed: false,
+            observers: Observers(Vec::new()),
+            cancel: None,
+            loop_detector: None,
         }
     }
 
+    /// Change what `,` stores at the end of the input, the default is to
+    /// leave the cell unchanged
+    pub fn set_eof_behavior(&mut self, eof: EofBehavior) {
+        self.eof = eof;
+    }
+
+    /// Change what happens when a cell overflows, the default is to wrap
+    pub fn set_overflow_policy(&mut self, overflow: OverflowPolicy) {
+        self.overflow = overflow;
+    }
+
+    /// Change what happens when the head moves off the end of the tape,
+    /// replacing the choice made by can_grow
+    pub fn set_tape_boundary(&mut self, boundary: TapeBoundary) {
+        self.tape.set_boundary(boundary);
+    }
+
+    /// Change how the tape grows when the head runs off the end of it,
+    /// this has no effect unless the tape boundary is Grow
+    pub fn set_tape_growth(&mut self, growth: TapeGrowth) {
+        self.tape.set_growth(growth);
+    }
+
+    /// Limit how much the program may do, the default is no limits.  Fails
+    /// with `TapeError::LimitExceeded`, changing nothing, if the tape is
+    /// already bigger than max_tape.
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) -> Result<(), TapeError> {
+        self.tape.set_limit(limits.max_tape)?;
+        self.limits = limits;
+        Ok(())
+    }
+
+    /// Stop with `VMError::Cancelled` soon after the token is cancelled, see
+    /// the `cancel` module
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    /// Fail with `VMError::InfiniteLoop` at a `]` when the machine gets
+    /// back to a state it has been in before, see the `cycle` module.  This
+    /// is off by default as it slows every loop down.
+    pub fn set_loop_detection(&mut self, detect: bool) {
+        self.loop_detector = if detect {
+            Some(LoopDetector::new(&self.tape))
+        } else {
+            None
+        };
+    }
+
+    /// Write a trace of every instruction `step` runs, see the `trace`
+    /// module for the format
+    pub fn set_trace_writer(&mut self, writer: impl Write + 'a) {
+        self.trace = Some(TraceWriter(Box::new(writer)));
+    }
+
+    /// Change where the `#` extension writes its dumps, the default is
+    /// stderr
+    pub fn set_dump_writer(&mut self, writer: impl Write + 'a) {
+        self.dump = DumpWriter(Box::new(writer));
+    }
+
+    /// Record the changes made by up to capacity of the most recent
+    /// instructions, so that they can be undone by `step_back`.  None stops
+    /// recording.  Either way the changes recorded so far are forgotten.
+    pub fn set_recording(&mut self, capacity: Option<usize>) {
+        self.history = capacity.map(History::new);
+    }
+
+    /// Return how many instructions `step_back` can undo
+    pub fn recorded_steps(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
+    /// Tell the observer about every instruction `step` runs, see the
+    /// `observer` module for the events
+    pub fn add_observer(&mut self, observer: impl VmObserver<T> + 'a) {
+        self.observers.0.push(Box::new(observer));
+    }
+
+    /// Pause `step` when the cell at index meets the condition, see the
+    /// `watch` module for how cells are indexed
+    pub fn add_watchpoint(&mut self, index: usize, condition: WatchCondition<T>) {
+        let position = self.position(index);
+        self.watchpoints.push(Watchpoint::new(position, condition));
+    }
+
+    /// Remove all of the watchpoints on the cell at index
+    pub fn remove_watchpoints(&mut self, index: usize) {
+        let position = self.position(index);
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.position != position);
+    }
+
+    /// Return the position of the cell at index, counted from the origin
+    fn position(&self, index: usize) -> isize {
+        index as isize - self.tape.origin() as isize
+    }
+
+    /// Return the watchpoints
+    pub fn watchpoints(&self) -> &[Watchpoint<T>] {
+        &self.watchpoints
+    }
+
+    /// Run the program until the program counter moves past the last
+    /// instruction, or an error occurs.  Watchpoints do not stop it.
     pub fn interpret<R, W>(&mut self, mut input: R, mut output: W) -> Result<(), VMError>
     where
         R: Read,
         W: Write,
     {
         loop {
-            let bf_instruction = self.program.commands()[self.program_counter];
-
-            let res = match bf_instruction.get_command() {
-                BFCommand::IncrementPointer => self.move_head_right(),
-                BFCommand::DecrementPointer => self.move_head_left(),
-                BFCommand::IncrementByte => self.wrapped_add(1),
-                BFCommand::DecrementByte => self.wrapped_sub(1),
-                BFCommand::OutputByte => self.output(&mut output),
-                BFCommand::InputByte => self.input(&mut input),
-                BFCommand::IfZeroJumpForward => self.jump_forward(),
-                BFCommand::IfNonZeroJumpBack => self.jump_back(),
-            };
-
-            if self.program_counter > self.program_counter_size {
-                break Ok(());
+            match self.step(&mut input, &mut output) {
+                StepOutcome::Continued | StepOutcome::Watchpoint { .. } => (),
+                StepOutcome::Halted => return Ok(()),
+                StepOutcome::Error(e) => return Err(e),
             }
+        }
+    }
 
-            match res {
-                Ok(s) => self.program_counter = s,
-                Err(e) => break Err(e),
+    /// Add bytes to the end of the input read by `resume`, this is also
+    /// read before the reader given to `step` or `interpret`
+    pub fn feed_input(&mut self, bytes: &[u8]) {
+        self.input_buffer.extend(bytes);
+    }
+
+    /// Say there will be no more input, so that once the fed input has been
+    /// read `,` stores according to the EOF behaviour instead of `resume`
+    /// returning NeedsInput
+    pub fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Run the program without blocking, until it needs input that has not
+    /// been fed in, writes output, finishes or fails.  Watchpoints do not
+    /// stop it.
+    pub fn resume(&mut self) -> RunState {
+        loop {
+            match self.current_instruction() {
+                None => return RunState::Halted,
+                Some(instruction)
+                    if instruction.get_command() == BFCommand::InputByte
+                        && self.input_buffer.is_empty()
+                        && !self.input_closed =>
+                {
+                    return RunState::NeedsInput
+                }
+                Some(_) => (),
+            }
+
+            let mut output = Vec::new();
+            match self.step(&mut std::io::empty(), &mut output) {
+                StepOutcome::Error(e) => return RunState::Error(e),
+                _ if !output.is_empty() => return RunState::Output(output),
+                StepOutcome::Halted => return RunState::Halted,
+                StepOutcome::Continued | StepOutcome::Watchpoint { .. } => (),
             }
         }
     }
 
-    fn increment_program_counter(&mut self) -> Result<usize, VMError> {
-        let mut next_pointer = self.program_counter; // Ask Question
-        next_pointer += 1;
-        if next_pointer == self.program_counter_size {
-            self.program_counter = next_pointer;
-            Ok(self.program_counter)
-        } else {
-            Err(VMError::ProgramOutOfBounds(
-                self.program.commands()[self.program_counter],
-            ))
+    /// Run the instruction at the program counter
+    pub fn step<R, W>(&mut self, input: &mut R, output: &mut W) -> StepOutcome
+    where
+        R: Read,
+        W: Write,
+    {
+        let bf_instruction = match self.current_instruction() {
+            Some(instruction) => *instruction,
+            None => return StepOutcome::Halted,
+        };
+        let program_counter = self.program_counter;
+        for observer in &mut self.observers.0 {
+            observer.on_step(program_counter, &bf_instruction);
+        }
+        if let Err(e) = cancel::check(&self.cancel, self.usage.steps, bf_instruction)
+            .and_then(|()| self.limits.step(&mut self.usage, bf_instruction))
+        {
+            return self.fail(e);
+        }
+        let (head, origin, before) = (self.tape.head(), self.tape.origin(), self.tape.current());
+        let mut consumed = None;
+
+        let res = match bf_instruction.get_command() {
+            BFCommand::IncrementPointer => self.move_head_right(),
+            BFCommand::DecrementPointer => self.move_head_left(),
+            BFCommand::IncrementByte => self.increment_cell(1),
+            BFCommand::DecrementByte => self.decrement_cell(1),
+            BFCommand::OutputByte => self.output(output),
+            BFCommand::InputByte => self.read_input(input).map(|(next, byte)| {
+                consumed = byte;
+                next
+            }),
+            BFCommand::IfZeroJumpForward => self.jump_forward(),
+            BFCommand::IfNonZeroJumpBack => self.jump_back(),
+            BFCommand::DebugDump => self.debug_dump(),
+        };
+
+        match res {
+            Ok(next) => {
+                if let Some(detector) = &mut self.loop_detector {
+                    match bf_instruction.get_command() {
+                        BFCommand::IncrementPointer | BFCommand::DecrementPointer => {
+                            detector.visit(&self.tape)
+                        }
+                        BFCommand::InputByte if consumed.is_some() => detector.reset(),
+                        BFCommand::IfNonZeroJumpBack
+                            if !before.is_zero()
+                                && detector.repeated(program_counter, &self.tape) =>
+                        {
+                            return self.fail(VMError::InfiniteLoop(bf_instruction.into()));
+                        }
+                        _ => (),
+                    }
+                }
+
+                self.program_counter = next;
+
+                if let Some(history) = &mut self.history {
+                    // Only instructions which leave the head where it was
+                    // change a cell
+                    let head_delta = (self.tape.head() as isize - self.tape.origin() as isize)
+                        - (head as isize - origin as isize);
+                    let cell_delta = if head_delta == 0 {
+                        self.tape.current().to_i128() - before.to_i128()
+                    } else {
+                        0
+                    };
+                    history.push(Change {
+                        program_counter,
+                        head_delta,
+                        cell_delta,
+                        input: consumed,
+                    });
+                }
+
+                if let Some(trace) = &mut self.trace {
+                    // Growing the tape to the left moves the cells along
+                    let record = TraceRecord {
+                        step: self.usage.steps,
+                        program_counter,
+                        instruction: bf_instruction,
+                        head,
+                        before,
+                        after: self.tape.cell(head + self.tape.origin() - origin),
+                    };
+                    if record.write_json(&mut trace.0).is_err() {
+                        return self.fail(VMError::TraceWriteError(bf_instruction.into()));
+                    }
+                }
+
+                if !self.observers.0.is_empty() {
+                    self.notify(bf_instruction, head, origin, before, consumed);
+                }
+
+                // Only the cell under the head can have changed, and only
+                // if the head did not move
+                let after = self.tape.current();
+                let position = self.position(self.tape.head());
+                let triggered = self.tape.head() == head
+                    && self
+                        .watchpoints
+                        .iter()
+                        .any(|watchpoint| watchpoint.is_triggered(position, before, after));
+
+                if triggered {
+                    StepOutcome::Watchpoint {
+                        index: self.tape.head(),
+                        instruction: bf_instruction,
+                    }
+                } else if self.program_counter < self.program_counter_size {
+                    StepOutcome::Continued
+                } else {
+                    StepOutcome::Halted
+                }
+            }
+            Err(e) => self.fail(e),
         }
     }
 
-    fn decrement_program_counter(&mut self) -> Result<usize, VMError> {
-        if self.program_counter == 0 {
-            Err(VMError::ProgramOutOfBounds(
-                self.program.commands()[self.program_counter],
-            ))
-        } else {
-            self.program_counter -= 1;
-            Ok(self.program_counter)
+    /// Tell the observers what the instruction which just ran did
+    fn notify(
+        &mut self,
+        instruction: InputInstruction,
+        head: usize,
+        origin: usize,
+        before: T,
+        consumed: Option<u8>,
+    ) {
+        // Growing the tape to the left moves the cells along
+        let from = head + self.tape.origin() - origin;
+        let (to, after) = (self.tape.head(), self.tape.current());
+        let command = instruction.get_command();
+        let output = match command {
+            BFCommand::OutputByte => after.to_output_bytes(),
+            _ => Vec::new(),
+        };
+
+        for observer in &mut self.observers.0 {
+            match command {
+                BFCommand::InputByte => observer.on_input(consumed),
+                BFCommand::OutputByte => observer.on_output(&output),
+                BFCommand::IfZeroJumpForward if !before.is_zero() => {
+                    observer.on_loop_enter(&instruction)
+                }
+                BFCommand::IfNonZeroJumpBack if before.is_zero() => {
+                    observer.on_loop_exit(&instruction)
+                }
+                _ => (),
+            }
+
+            if from != to {
+                observer.on_head_move(from, to);
+            } else if before != after {
+                observer.on_cell_change(to, before, after);
+            }
         }
     }
 
-    pub fn get_current_cell(&self) -> &InputInstruction {
-        &self.program.commands()[self.tape_pointer]
+    /// Tell the observers about an error, returning it as the outcome
+    fn fail(&mut self, error: VMError) -> StepOutcome {
+        let error = self.with_state(error);
+        for observer in &mut self.observers.0 {
+            observer.on_error(&error);
+        }
+        StepOutcome::Error(error)
     }
 
-    pub fn move_head_left(&mut self) -> Result<usize, VMError> {
-        if self.tape_pointer > 0 {
-            self.tape_pointer -= 1;
-            self.decrement_program_counter()
-        } else {
-            Err(VMError::InvalidHeadPosition(
-                self.program.commands()[self.tape_pointer], // this needs changing
-            ))
+    /// Undo the most recent recorded instruction, returning what it changed,
+    /// or None if there is nothing left to undo.  See the `history` module.
+    pub fn step_back(&mut self) -> Option<Change> {
+        let change = self.history.as_mut()?.pop()?;
+
+        let head = self.tape.head() as isize - change.head_delta;
+        self.tape.set_head(head as usize);
+        let cell = self.tape.current().to_i128() - change.cell_delta;
+        self.tape.set_current(T::from_i128(cell));
+        if let Some(byte) = change.input {
+            self.input_buffer.push_front(byte);
+        }
+
+        // The states the detector has seen are now ahead of the machine
+        if let Some(detector) = &mut self.loop_detector {
+            detector.reset();
         }
+
+        self.program_counter = change.program_counter;
+        self.usage.steps -= 1;
+        Some(change)
     }
 
-    pub fn move_head_right(&mut self) -> Result<usize, VMError> {
-        if self.tape_pointer < (self.tape_size - 1) {
-            self.increment_program_counter()
-        } else {
-            Err(VMError::InvalidHeadPosition(
-                self.program.commands()[self.tape_pointer],
-            ))
+    /// Step back until predicate returns true after an instruction has been
+    /// undone, returning false if the recording ran out first
+    pub fn step_back_until<P>(&mut self, mut predicate: P) -> bool
+    where
+        P: FnMut(&Self) -> bool,
+    {
+        while self.step_back().is_some() {
+            if predicate(self) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Step until predicate returns true after an instruction has run, or
+    /// the program halts or fails, returning the outcome of the last step
+    pub fn run_until<R, W, P>(
+        &mut self,
+        input: &mut R,
+        output: &mut W,
+        mut predicate: P,
+    ) -> StepOutcome
+    where
+        R: Read,
+        W: Write,
+        P: FnMut(&Self) -> bool,
+    {
+        loop {
+            let outcome = self.step(input, output);
+            match outcome {
+                StepOutcome::Continued if !predicate(self) => (),
+                _ => return outcome,
+            }
         }
     }
 
-    pub fn wrapped_add(&mut self, num: u8) -> Result<usize, VMError> {
-        self.tape[self.tape_pointer].wrapping_increment(num);
-        Ok(self.program_counter)
+    /// Return the program being run
+    pub fn program(&self) -> &'a BFProgram {
+        self.program
     }
 
-    pub fn wrapped_sub(&mut self, num: u8) -> Result<usize, VMError> {
-        self.tape[self.tape_pointer].wrapping_decrement(num);
-        Ok(self.program_counter)
+    /// Return the number of instructions run so far
+    pub fn steps(&self) -> u64 {
+        self.usage.steps
     }
 
-    // [
-    pub fn jump_forward(&mut self) -> Result<usize, VMError> {
-        let mut buffer: [u8; 1] = [0u8; 1];
-        buffer[0] = self.tape[self.tape_pointer].into(); // Type T into u8
+    /// Return the index of the next instruction to run
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
 
-        if buffer[0] == 0 {
-            let iter = self.program.commands().iter().skip(self.program_counter);
+    /// Return the next instruction to run, None once the program has finished
+    pub fn current_instruction(&self) -> Option<&InputInstruction> {
+        self.program.commands().get(self.program_counter)
+    }
 
-            let last_instruct = self.program.commands().iter().nth(self.program_counter);
+    /// Return the tape
+    pub fn tape(&self) -> &Tape<T> {
+        &self.tape
+    }
 
-            for instruct in iter {
-                let a_char: char = BFCommand::to_char(instruct.get_command());
+    /// Return the index of the cell under the head
+    pub fn tape_pointer(&self) -> usize {
+        self.tape.head()
+    }
 
-                if a_char == ']' {
-                    self.program_counter += 1;
-                    return Ok(self.program_counter);
-                }
-                self.program_counter += 1;
-            }
-            Err(VMError::NestImbalance(*last_instruct.unwrap()))
-        } else {
-            self.program_counter += 1;
-            Ok(self.program_counter)
+    /// Return the cells in range, cut short at the ends of the tape
+    pub fn tape_slice(&self, range: Range<usize>) -> &[T] {
+        let cells = self.tape.cells();
+        let end = range.end.min(cells.len());
+        &cells[range.start.min(end)..end]
+    }
+
+    /// Return the state of the machine, to be restored later
+    pub fn snapshot(&self) -> VmState<T> {
+        VmState {
+            program_length: self.program_counter_size,
+            program_counter: self.program_counter,
+            steps: self.usage.steps,
+            head: self.tape.head(),
+            origin: self.tape.origin(),
+            cells: self.tape.cells().to_vec(),
+            boundary: self.tape.boundary(),
+            growth: self.tape.growth(),
+            overflow: self.overflow,
+            eof: self.eof,
         }
     }
 
-    // ]
-    pub fn jump_back(&mut self) -> Result<usize, VMError> {
-        let mut buffer: [u8; 1] = [0u8; 1];
-        buffer[0] = self.tape[self.tape_pointer].into(); // Type T into u8
-
-        if buffer[0] != 0 {
-            let instruct = self.program.commands()[self.program_counter];
-            let first_instuct = instruct.clone();
-            let mut found = false;
-
-            while !found || self.program_counter > 0 {
-                let instruct = self.program.commands()[self.program_counter];
-                let a_char: char = BFCommand::to_char(instruct.get_command());
-
-                if a_char == '[' {
-                    self.program_counter -= 1;
-                    found = true;
-                    break;
-                }
-                self.program_counter -= 1;
-            }
+    /// Carry on from a state returned by snapshot, which must be of a machine
+    /// running the same program.  Input and output are not part of the
+    /// state, so a program which has read input must be given the rest of it.
+    pub fn restore(&mut self, state: VmState<T>) -> Result<(), SnapshotError> {
+        if state.program_length != self.program_counter_size {
+            return Err(SnapshotError::ProgramMismatch);
+        }
+        if state.program_counter > self.program_counter_size {
+            return Err(SnapshotError::Corrupt("program counter"));
+        }
+        if state.head >= state.cells.len() || state.origin >= state.cells.len() {
+            return Err(SnapshotError::Corrupt("head position"));
+        }
 
-            if !found {
-                Err(VMError::NestImbalance(first_instuct))
-            } else {
-                self.program_counter += 1;
-                Ok(self.program_counter)
-            }
+        let mut tape = Tape::from_parts(
+            state.cells,
+            state.head,
+            state.origin,
+            state.boundary,
+            state.growth,
+        );
+        if tape.set_limit(self.limits.max_tape).is_err() {
+            return Err(SnapshotError::TapeLimitExceeded);
+        }
+
+        self.program_counter = state.program_counter;
+        self.usage.steps = state.steps;
+        self.tape = tape;
+        self.overflow = state.overflow;
+        self.eof = state.eof;
+        if let Some(history) = &mut self.history {
+            *history = History::new(history.capacity());
+        }
+        if self.loop_detector.is_some() {
+            self.loop_detector = Some(LoopDetector::new(&self.tape));
+        }
+        Ok(())
+    }
+
+    /// Return the next program counter, which may be one past the last
+    /// instruction to signal the end of the program
+    fn increment_program_counter(&self) -> Result<usize, VMError> {
+        let next_pointer = self.program_counter + 1;
+        if next_pointer <= self.program_counter_size {
+            Ok(next_pointer)
         } else {
-            self.program_counter += 1;
-            Ok(self.program_counter)
+            Err(self.error(VMError::ProgramOutOfBounds))
         }
     }
 
-    pub fn has_matching_brackets(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
-        let mut balanced = true;
-        let mut local_stack: Vec<&InputInstruction> = Vec::new();
+    /// Return whether the tape can grow
+    pub fn can_grow(&self) -> bool {
+        self.tape.can_grow()
+    }
 
-        for bfinstruction in self.program.commands().iter() {
-            if !balanced {
-                break;
-            }
+    /// Return the instruction at the program counter, which must not have
+    /// run off the end of the program.  See current_instruction.
+    pub fn get_current_cell(&self) -> &InputInstruction {
+        &self.program.commands()[self.program_counter]
+    }
 
-            let a_char: char = BFCommand::to_char(bfinstruction.get_command());
-            if a_char == '[' {
-                local_stack.push(bfinstruction);
-            } else if a_char == ']' {
-                if local_stack.is_empty() {
-                    balanced = false;
-                    println!("Bracket Error {}", bfinstruction);
-                } else {
-                    local_stack.pop();
-                }
-            }
+    /// Turn a failed head movement into the error for the current instruction
+    fn tape_error(&self, error: TapeError) -> VMError {
+        match error {
+            TapeError::HeadOutOfBounds => self.error(VMError::InvalidHeadPosition),
+            TapeError::TooBig => self.error(VMError::TapeTooBig),
+            TapeError::LimitExceeded => self.error(VMError::TapeLimitExceeded),
         }
+    }
 
-        if !balanced || !local_stack.is_empty() {
-            if !local_stack.is_empty() {
-                let instruct: &InputInstruction = local_stack.pop().unwrap();
-                println!("Bracket Error {}", instruct);
-            }
+    /// Build an error for the current instruction, with the state of the
+    /// machine now
+    fn error(&self, kind: fn(ErrorContext) -> VMError) -> VMError {
+        self.with_state(kind(self.program.commands()[self.program_counter].into()))
+    }
 
-            return Ok(false);
+    /// Fill in the head, cell and steps of an error from the machine
+    fn with_state(&self, error: VMError) -> VMError {
+        error.with_state(
+            self.tape.head(),
+            self.tape.current().to_i128(),
+            self.usage.steps,
+        )
+    }
+
+    pub fn move_head_left(&mut self) -> Result<usize, VMError> {
+        self.move_head(-1)
+    }
+
+    pub fn move_head_right(&mut self) -> Result<usize, VMError> {
+        self.move_head(1)
+    }
+
+    /// Move the head, the tape itself keeps to the tape limit
+    fn move_head(&mut self, offset: isize) -> Result<usize, VMError> {
+        if let Err(e) = self.tape.move_by(offset) {
+            return Err(self.tape_error(e));
         }
 
-        Ok(true)
+        self.increment_program_counter()
     }
 
-    // TODO - this is still not right
-    pub fn input(&mut self, reader: &mut impl Read) -> Result<usize, VMError> {
-        let mut buffer: [u8; 1] = [0u8; 1];
+    /// Add num to the cell at the head, according to the overflow policy
+    pub fn increment_cell(&mut self, num: u8) -> Result<usize, VMError> {
+        let mut cell = self.tape.current();
+        let value = match self.overflow {
+            OverflowPolicy::Wrap => Some(cell.wrapping_increment(num)),
+            OverflowPolicy::Saturate => Some(cell.saturating_increment(num)),
+            OverflowPolicy::Trap => cell.checked_increment(num),
+        };
+        self.store_cell(value)
+    }
 
-        let instruct = self.program.commands()[self.program_counter];
+    /// Subtract num from the cell at the head, according to the overflow policy
+    pub fn decrement_cell(&mut self, num: u8) -> Result<usize, VMError> {
+        let mut cell = self.tape.current();
+        let value = match self.overflow {
+            OverflowPolicy::Wrap => Some(cell.wrapping_decrement(num)),
+            OverflowPolicy::Saturate => Some(cell.saturating_decrement(num)),
+            OverflowPolicy::Trap => cell.checked_decrement(num),
+        };
+        self.store_cell(value)
+    }
 
-        match reader.read(&mut buffer) {
-            Ok(s) => {
-                self.tape.insert(self.tape_pointer, buffer[0].into());
-                Ok(s)
+    /// Store the new value of the cell at the head, None means it overflowed
+    fn store_cell(&mut self, value: Option<T>) -> Result<usize, VMError> {
+        match value {
+            Some(value) => {
+                self.tape.set_current(value);
+                self.increment_program_counter()
             }
-            Err(_) => Err(VMError::IOReadError(instruct)),
+            None => Err(self.error(VMError::CellOverflow)),
         }
     }
 
-    pub fn output(&mut self, writer: &mut impl Write) -> Result<usize, VMError> {
-        let mut buffer: [u8; 1] = [0u8; 1];
-        let instruct = self.program.commands()[self.program_counter];
-        buffer[0] = self.tape[self.tape_pointer].into(); // Type T into u8
+    /// Jump to the matching bracket, taken from the jump table in the
+    /// program, returning the instruction after it
+    fn jump_to_matching_bracket(&self) -> Result<usize, VMError> {
+        match self.program.matching_bracket(self.program_counter) {
+            Some(index) => Ok(index + 1),
+            None => Err(self.error(VMError::NestImbalance)),
+        }
+    }
 
-        println!("{}", buffer[0]);
-        //writer.write_all(b"hello world");
+    /// `[` - if the cell at the head is zero, jump forward to the
+    /// instruction after the matching `]`
+    pub fn jump_forward(&mut self) -> Result<usize, VMError> {
+        if self.tape.current().is_zero() {
+            self.jump_to_matching_bracket()
+        } else {
+            self.increment_program_counter()
+        }
+    }
 
-        match writer.write(&buffer) {
-            Ok(s) => {
-                //writer.flush();
-                Ok(s)
-            }
-            Err(_) => Err(VMError::IOWriteError(instruct)),
+    /// `]` - if the cell at the head is nonzero, jump back to the
+    /// instruction after the matching `[`
+    pub fn jump_back(&mut self) -> Result<usize, VMError> {
+        if !self.tape.current().is_zero() {
+            self.jump_to_matching_bracket()
+        } else {
+            self.increment_program_counter()
+        }
+    }
+
+    /// `,` - read a byte into the cell at the head, at the end of the input
+    /// the cell is set according to the EOF behaviour
+    pub fn input(&mut self, reader: &mut impl Read) -> Result<usize, VMError> {
+        self.read_input(reader).map(|(next, _)| next)
+    }
+
+    /// Carry out `,`, returning the byte read as well.  Input fed in or
+    /// given back by step_back is read before the reader.
+    fn read_input(&mut self, reader: &mut impl Read) -> Result<(usize, Option<u8>), VMError> {
+        // The error needs the instruction as well as the IO error, so we
+        // can't convert it with a From impl and use `?` here
+        let byte = match self.input_buffer.pop_front() {
+            Some(byte) => Some(byte),
+            None => read_byte(reader).map_err(|_| self.error(VMError::IOReadError))?,
+        };
+        let value = match byte {
+            Some(byte) => T::from_input_byte(byte),
+            None => match self.eof {
+                EofBehavior::Unchanged => self.tape.current(),
+                EofBehavior::Zero => T::default(),
+                EofBehavior::MinusOne => T::all_ones(),
+            },
+        };
+
+        self.tape.set_current(value);
+        Ok((self.increment_program_counter()?, byte))
+    }
+
+    pub fn output(&mut self, writer: &mut impl Write) -> Result<usize, VMError> {
+        let instruct = self.program.commands()[self.program_counter];
+        let buffer = self.tape.current().to_output_bytes();
+        self.limits
+            .output(&mut self.usage, buffer.len(), instruct)
+            .map_err(|e| self.with_state(e))?;
+
+        match writer.write_all(&buffer) {
+            Ok(()) => self.increment_program_counter(),
+            Err(_) => Err(self.error(VMError::IOWriteError)),
         }
     }
+
+    /// `#` - write the state of the machine to the dump writer, this is
+    /// only parsed as a command with the debug_dump extension
+    pub fn debug_dump(&mut self) -> Result<usize, VMError> {
+        let instruct = self.program.commands()[self.program_counter];
+        self.dump
+            .dump(instruct, self.program_counter, &self.tape)
+            .map_err(|e| self.with_state(e))?;
+        self.increment_program_counter()
+    }
 }
 
 impl<'a, T> fmt::Display for BFVirtualMachine<'a, T> {
@@ -353,8 +1158,21 @@ impl<'a, T> fmt::Display for BFVirtualMachine<'a, T> {
 
 #[cfg(test)]
 mod tests {
+    use super::tape::{GrowthPolicy, TapeBoundary, TapeGrowth};
     use super::BFVirtualMachine;
+    use super::CancelToken;
     use super::CellKind;
+    use super::EofBehavior;
+    use super::OverflowPolicy;
+    use super::ResourceLimits;
+    use super::RunState;
+    use super::SnapshotError;
+    use super::StepOutcome;
+    use super::TapeError;
+    use super::TraceRecord;
+    use super::VMError;
+    use super::VmState;
+    use super::WatchCondition;
     use bft_types::BFCommand;
     use bft_types::BFProgram;
     use std::env;
@@ -390,7 +1208,7 @@ mod tests {
             let result = virtual_machine.move_head_right();
 
             match result {
-                Ok(num) => (),
+                Ok(_num) => (),
                 Err(_e) => (),
             }
 
@@ -400,6 +1218,19 @@ mod tests {
             println!("Right: {}", num);
         }
 
+        // Moving the head does not run the program
+        assert_eq!(virtual_machine.tape_pointer(), 2);
+        assert_eq!(
+            virtual_machine.get_current_cell().get_command(),
+            BFCommand::IncrementByte
+        );
+
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        for _ in 0..2 {
+            virtual_machine.step(&mut input, &mut output);
+        }
+
         let instruction = virtual_machine.get_current_cell();
 
         assert_eq!(BFCommand::to_char(instruction.get_command()), '-');
@@ -420,7 +1251,7 @@ mod tests {
             let result = virtual_machine.move_head_right();
 
             match result {
-                Ok(num) => (),
+                Ok(_num) => (),
                 Err(_e) => (),
             }
 
@@ -430,13 +1261,21 @@ mod tests {
             println!("Right: {}", num);
         }
 
-        let result = virtual_machine.move_head_left();
+        let _result = virtual_machine.move_head_left();
 
         /*match result {
             Ok(()) => (),
             Err(_e) => (),
         }*/
 
+        assert_eq!(virtual_machine.tape_pointer(), 1);
+
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+        for _ in 0..3 {
+            virtual_machine.step(&mut input, &mut output);
+        }
+
         let instruction = virtual_machine.get_current_cell();
 
         assert_eq!(BFCommand::to_char(instruction.get_command()), '[');
@@ -468,10 +1307,10 @@ mod tests {
     fn test_add_wrap_u8() {
         let mut aa: u8 = 252;
         assert_eq!(aa, 252);
-        println!("Max Value:{}", u8::max_value());
+        println!("Max Value:{}", u8::MAX);
 
         let number_to_add: u8 = 7;
-        let new_num = number_to_add - (u8::max_value() - aa);
+        let new_num = number_to_add - (u8::MAX - aa);
         println!("New Num: {}", new_num);
 
         aa = aa.wrapping_increment(number_to_add);
@@ -492,12 +1331,13 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_variables, unused_mut)]
     fn test_read_write1() {
         let mut buff = Cursor::new(vec![15]);
 
         let mut path = env::current_dir().unwrap();
 
-        path.set_file_name("bft/inputbf.txt");
+        path.set_file_name("inputbf.txt");
 
         let program = BFProgram::new(path);
 
@@ -515,12 +1355,13 @@ mod tests {
     }
 
     #[test]
+    #[allow(unused_variables)]
     fn test_read_write2() {
         let mut buff = Cursor::new(vec![15]);
 
         let mut path = env::current_dir().unwrap();
 
-        path.set_file_name("bft/inputbf.txt");
+        path.set_file_name("inputbf.txt");
 
         let program = BFProgram::new(path);
 
@@ -541,4 +1382,731 @@ mod tests {
             Err(_e) => println!("Read Error"),
         };
     }
+
+    #[test]
+    fn nested_loops_jump_to_matching_bracket() {
+        let program = BFProgram::from_str("nested.bf", "++[>++[>+++<-]<-]").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> =
+            BFVirtualMachine::new(&program, false, 30000);
+
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        assert_eq!(virtual_machine.tape.cells()[0], 0);
+        assert_eq!(virtual_machine.tape.cells()[1], 0);
+        assert_eq!(virtual_machine.tape.cells()[2], 12);
+    }
+
+    #[test]
+    fn hello_world_runs() {
+        let mut path = env::current_dir().unwrap();
+
+        path.set_file_name("helloworld.bf");
+
+        let program = BFProgram::new(path);
+
+        let mut virtual_machine: BFVirtualMachine<u8> =
+            BFVirtualMachine::new(&program, false, 30000);
+
+        let mut output = Vec::new();
+        virtual_machine
+            .interpret(Cursor::new(vec![]), &mut output)
+            .unwrap();
+
+        assert_eq!(output, b"Hello World!\n");
+    }
+
+    #[test]
+    fn fixed_tape_errors_at_the_end() {
+        let program = BFProgram::from_str("right.bf", ">>\n>").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 3);
+
+        match virtual_machine.interpret(Cursor::new(vec![]), Vec::new()) {
+            Err(VMError::InvalidHeadPosition(context)) => {
+                assert_eq!(context.instruction.line_number(), 1);
+                assert_eq!(context.instruction.column_number(), 0);
+            }
+            other => panic!("Expected a head position error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn growable_tape_grows() {
+        let program = BFProgram::from_str("grow.bf", ">>>>>+").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, true, 2);
+
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        assert_eq!(virtual_machine.tape.cells()[5], 1);
+    }
+
+    #[test]
+    fn bidirectional_tape_grows_left() {
+        let program = BFProgram::from_str("left.bf", "+<<+").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, true, 4);
+        virtual_machine.set_tape_growth(TapeGrowth {
+            policy: GrowthPolicy::Chunk(8),
+            max_size: Some(64),
+            bidirectional: true,
+        });
+
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        let origin = virtual_machine.tape.origin();
+        assert_eq!(virtual_machine.tape.cells()[origin], 1);
+        assert_eq!(virtual_machine.tape.cells()[origin - 2], 1);
+    }
+
+    #[test]
+    fn growable_tape_stops_at_max_size() {
+        let program = BFProgram::from_str("max.bf", ">>>>").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, true, 2);
+        virtual_machine.set_tape_growth(TapeGrowth {
+            policy: GrowthPolicy::Double,
+            max_size: Some(4),
+            bidirectional: false,
+        });
+
+        match virtual_machine.interpret(Cursor::new(vec![]), Vec::new()) {
+            Err(VMError::TapeTooBig(context)) => assert_eq!(context.instruction.column_number(), 3),
+            other => panic!("Expected a tape size error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tape_boundary_wraps_and_clamps() {
+        let program = BFProgram::from_str("boundary.bf", "<+").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine.set_tape_boundary(TapeBoundary::Wrap);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+        assert_eq!(virtual_machine.tape.cells(), &[0, 0, 0, 0, 1]);
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine.set_tape_boundary(TapeBoundary::Clamp);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+        assert_eq!(virtual_machine.tape.cells(), &[1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_saturate_and_check_u8() {
+        let mut aa: u8 = 250;
+
+        assert_eq!(aa.saturating_increment(10), 255);
+        assert_eq!(aa.checked_increment(10), None);
+        assert_eq!(aa.checked_increment(5), Some(255));
+
+        aa = 3;
+        assert_eq!(aa.saturating_decrement(10), 0);
+        assert_eq!(aa.checked_decrement(4), None);
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let program = BFProgram::from_str("overflow.bf", "+\n--").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+        assert_eq!(virtual_machine.tape.current(), 255);
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine.set_overflow_policy(OverflowPolicy::Saturate);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+        assert_eq!(virtual_machine.tape.current(), 0);
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine.set_overflow_policy(OverflowPolicy::Trap);
+        match virtual_machine.interpret(Cursor::new(vec![]), Vec::new()) {
+            Err(VMError::CellOverflow(context)) => {
+                assert_eq!(context.instruction.line_number(), 1);
+                assert_eq!(context.instruction.column_number(), 1);
+            }
+            other => panic!("Expected a cell overflow, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn input_stores_into_the_cell() {
+        let program = BFProgram::from_str("input.bf", ",>,").unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine
+            .interpret(Cursor::new(vec![7, 9]), Vec::new())
+            .unwrap();
+
+        assert_eq!(virtual_machine.tape.cells(), &[7, 9, 0, 0, 0]);
+    }
+
+    #[test]
+    fn eof_behaviors() {
+        let program = BFProgram::from_str("eof.bf", "+++,").unwrap();
+        let expected = [
+            (EofBehavior::Unchanged, 3),
+            (EofBehavior::Zero, 0),
+            (EofBehavior::MinusOne, 255),
+        ];
+
+        for (eof, cell) in expected.iter() {
+            let mut virtual_machine: BFVirtualMachine<u8> =
+                BFVirtualMachine::new(&program, false, 5);
+            virtual_machine.set_eof_behavior(*eof);
+            virtual_machine
+                .interpret(Cursor::new(vec![]), Vec::new())
+                .unwrap();
+
+            assert_eq!(virtual_machine.tape.current(), *cell);
+        }
+    }
+
+    #[test]
+    fn wider_cells_wrap_at_their_own_size() {
+        let mut aa: u16 = 255;
+        assert_eq!(aa.wrapping_increment(1), 256);
+        aa = u16::MAX;
+        assert_eq!(aa.wrapping_increment(1), 0);
+        assert_eq!(aa.checked_increment(1), None);
+
+        let mut bb: i8 = -128;
+        assert_eq!(bb.wrapping_decrement(1), 127);
+        assert_eq!(bb.saturating_decrement(1), -128);
+        assert_eq!(i8::all_ones(), -1);
+        assert_eq!(i8::from_input_byte(255), -1);
+
+        let cc: u64 = u64::MAX;
+        assert_eq!(cc.add(1), (0, true));
+        assert_eq!(CellKind::sub(0i32, 1), (-1, false));
+        assert_eq!(u32::from_input_byte(65).to_output_bytes(), vec![65]);
+        assert_eq!(<u32 as CellKind>::NAME, "u32");
+    }
+
+    #[test]
+    fn sixteen_bit_cells_hold_large_values() {
+        // 256 is zero for a u8 cell, so the loop only runs for wider cells
+        let program =
+            BFProgram::from_str("wide.bf", "++++++++++++++++[>++++++++++++++++<-]>[>+<[-]]")
+                .unwrap();
+
+        let mut virtual_machine: BFVirtualMachine<u16> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+        assert_eq!(virtual_machine.tape.cells()[..3], [0, 0, 1]);
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 5);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+        assert_eq!(virtual_machine.tape.cells()[..3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn step_runs_one_instruction() {
+        let program = BFProgram::from_str("step.bf", "+>++\n<-").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        assert!(matches!(
+            virtual_machine.step(&mut input, &mut output),
+            StepOutcome::Continued
+        ));
+        assert_eq!(virtual_machine.program_counter(), 1);
+        assert_eq!(virtual_machine.tape_slice(0..2), [1, 0]);
+
+        virtual_machine.step(&mut input, &mut output);
+        assert_eq!(virtual_machine.tape_pointer(), 1);
+
+        let outcome = virtual_machine.run_until(&mut input, &mut output, |vm| {
+            vm.current_instruction().unwrap().line_number() == 1
+        });
+        assert!(matches!(outcome, StepOutcome::Continued));
+        assert_eq!(virtual_machine.program_counter(), 4);
+        assert_eq!(virtual_machine.tape_slice(0..10), [1, 2, 0, 0]);
+
+        let outcome = virtual_machine.run_until(&mut input, &mut output, |_| false);
+        assert!(matches!(outcome, StepOutcome::Halted));
+        assert!(virtual_machine.current_instruction().is_none());
+        assert_eq!(virtual_machine.tape_slice(0..2), [0, 2]);
+        assert!(matches!(
+            virtual_machine.step(&mut input, &mut output),
+            StepOutcome::Halted
+        ));
+    }
+
+    #[test]
+    fn errors_say_where_the_machine_was() {
+        let program = BFProgram::from_str("where.bf", "+++>--\n<<").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+
+        let error = virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap_err();
+        let context = error.context();
+        assert_eq!(context.instruction.line_number(), 1);
+        assert_eq!(context.instruction.column_number(), 1);
+        assert_eq!(context.head, 0);
+        assert_eq!(context.cell, 3);
+        assert_eq!(context.steps, 8);
+        assert_eq!(
+            error.to_string(),
+            "[Line 1, Col 1] <: the head moved off the end of the tape \
+             (head at #0, cell 3, after 8 steps)"
+        );
+
+        // Errors from the limits are given the state too
+        let program = BFProgram::from_str("limit.bf", ">+[-+]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<i8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine.set_overflow_policy(OverflowPolicy::Trap);
+        virtual_machine
+            .set_resource_limits(ResourceLimits {
+                max_steps: Some(10),
+                ..ResourceLimits::default()
+            })
+            .unwrap();
+        match virtual_machine.interpret(Cursor::new(vec![]), Vec::new()) {
+            Err(VMError::StepLimitExceeded(context)) => {
+                assert_eq!(context.head, 1);
+                assert_eq!(context.steps, 10);
+            }
+            other => panic!("Expected the step limit, got {:?}", other),
+        }
+
+        // As are errors from calling the commands directly
+        let program = BFProgram::from_str("direct.bf", ".").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine
+            .set_resource_limits(ResourceLimits {
+                max_output: Some(0),
+                ..ResourceLimits::default()
+            })
+            .unwrap();
+        virtual_machine.move_head_right().unwrap();
+        virtual_machine.increment_cell(2).unwrap();
+        match virtual_machine.output(&mut Vec::new()) {
+            Err(VMError::OutputLimitExceeded(context)) => {
+                assert_eq!(context.head, 1);
+                assert_eq!(context.cell, 2);
+            }
+            other => panic!("Expected the output limit, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failed_step_stays_on_the_instruction() {
+        let program = BFProgram::from_str("fail.bf", "+<").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        let outcome = virtual_machine.run_until(&mut input, &mut output, |_| false);
+        assert!(matches!(
+            outcome,
+            StepOutcome::Error(VMError::InvalidHeadPosition(_))
+        ));
+        assert_eq!(virtual_machine.program_counter(), 1);
+    }
+
+    #[test]
+    fn watchpoints_pause_step_but_not_interpret() {
+        let program = BFProgram::from_str("watch.bf", "+++[>++<-]>[>+<-]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine.add_watchpoint(1, WatchCondition::Equals(4));
+        virtual_machine.add_watchpoint(2, WatchCondition::Changed);
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        match virtual_machine.run_until(&mut input, &mut output, |_| false) {
+            StepOutcome::Watchpoint { index, instruction } => {
+                assert_eq!(index, 1);
+                assert_eq!(instruction.column_number(), 6);
+            }
+            other => panic!("Expected a watchpoint, got {:?}", other),
+        }
+        assert_eq!(virtual_machine.tape_slice(0..3), [2, 4, 0]);
+
+        match virtual_machine.run_until(&mut input, &mut output, |_| false) {
+            StepOutcome::Watchpoint { index, instruction } => {
+                assert_eq!(index, 2);
+                assert_eq!(instruction.column_number(), 13);
+            }
+            other => panic!("Expected a watchpoint, got {:?}", other),
+        }
+
+        virtual_machine.remove_watchpoints(2);
+        assert_eq!(virtual_machine.watchpoints().len(), 1);
+
+        // Cell 1 reaches 4 again on its way back down to zero
+        match virtual_machine.run_until(&mut input, &mut output, |_| false) {
+            StepOutcome::Watchpoint { index, instruction } => {
+                assert_eq!(index, 1);
+                assert_eq!(instruction.column_number(), 15);
+            }
+            other => panic!("Expected a watchpoint, got {:?}", other),
+        }
+        assert_eq!(virtual_machine.tape_slice(0..3), [0, 4, 2]);
+
+        assert!(matches!(
+            virtual_machine.run_until(&mut input, &mut output, |_| false),
+            StepOutcome::Halted
+        ));
+        assert_eq!(virtual_machine.tape_slice(0..3), [0, 0, 6]);
+
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine.add_watchpoint(1, WatchCondition::Changed);
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+        assert_eq!(virtual_machine.tape_slice(0..3), [0, 0, 6]);
+    }
+
+    #[test]
+    fn watchpoints_follow_a_growing_tape() {
+        let program = BFProgram::from_str("watch.bf", "<<+>>+").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, true, 4);
+        virtual_machine.set_tape_growth(TapeGrowth {
+            policy: GrowthPolicy::Chunk(2),
+            max_size: None,
+            bidirectional: true,
+        });
+        virtual_machine.add_watchpoint(0, WatchCondition::Changed);
+        let mut input = Cursor::new(vec![]);
+        let mut output = Vec::new();
+
+        // The cell which was at index 0 has moved along to make room
+        match virtual_machine.run_until(&mut input, &mut output, |_| false) {
+            StepOutcome::Watchpoint { index, instruction } => {
+                assert_eq!(index, 2);
+                assert_eq!(instruction.column_number(), 5);
+            }
+            other => panic!("Expected a watchpoint, got {:?}", other),
+        }
+        assert_eq!(virtual_machine.tape_slice(0..3), [1, 0, 1]);
+
+        virtual_machine.remove_watchpoints(2);
+        assert!(virtual_machine.watchpoints().is_empty());
+    }
+
+    #[test]
+    fn resource_limits_stop_the_program() {
+        let program = BFProgram::from_str("limits.bf", "+[]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine
+            .set_resource_limits(ResourceLimits {
+                max_steps: Some(100),
+                ..ResourceLimits::default()
+            })
+            .unwrap();
+        match virtual_machine.interpret(Cursor::new(vec![]), Vec::new()) {
+            // `]` jumps back to itself, so that is where it stops
+            Err(VMError::StepLimitExceeded(context)) => {
+                assert_eq!(context.instruction.column_number(), 2)
+            }
+            other => panic!("Expected the step limit, got {:?}", other),
+        }
+        assert_eq!(virtual_machine.steps(), 100);
+
+        let program = BFProgram::from_str("limits.bf", "+[..]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine
+            .set_resource_limits(ResourceLimits {
+                max_output: Some(5),
+                ..ResourceLimits::default()
+            })
+            .unwrap();
+        let mut output = Vec::new();
+        assert!(matches!(
+            virtual_machine.interpret(Cursor::new(vec![]), &mut output),
+            Err(VMError::OutputLimitExceeded(_))
+        ));
+        assert_eq!(output.len(), 5);
+
+        let program = BFProgram::from_str("limits.bf", "+[>+]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, true, 4);
+        virtual_machine
+            .set_resource_limits(ResourceLimits {
+                max_tape: Some(16),
+                ..ResourceLimits::default()
+            })
+            .unwrap();
+        assert!(matches!(
+            virtual_machine.interpret(Cursor::new(vec![]), Vec::new()),
+            Err(VMError::TapeLimitExceeded(_))
+        ));
+        assert!(virtual_machine.tape().len() <= 16);
+
+        // A tape which starts bigger than the limit is refused
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, true, 32);
+        assert_eq!(
+            virtual_machine.set_resource_limits(ResourceLimits {
+                max_tape: Some(16),
+                ..ResourceLimits::default()
+            }),
+            Err(TapeError::LimitExceeded)
+        );
+    }
+
+    #[test]
+    fn trace_errors_are_not_output_errors() {
+        let program = BFProgram::from_str("trace.bf", "+.").unwrap();
+        let mut trace = [0u8; 0];
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine.set_trace_writer(&mut trace[..]);
+
+        match virtual_machine.step(&mut Cursor::new(vec![]), &mut Vec::new()) {
+            StepOutcome::Error(VMError::TraceWriteError(context)) => {
+                assert_eq!(context.cell, 1);
+                assert_eq!(context.steps, 1);
+            }
+            other => panic!("Expected a trace error, got {:?}", other),
+        }
+        assert_eq!(virtual_machine.program_counter(), 1);
+    }
+
+    #[test]
+    fn trace_has_a_record_per_step() {
+        let program = BFProgram::from_str("trace.bf", "+>\n-").unwrap();
+        let mut trace = Vec::new();
+        {
+            let mut virtual_machine: BFVirtualMachine<u8> =
+                BFVirtualMachine::new(&program, false, 4);
+            virtual_machine.set_trace_writer(&mut trace);
+            virtual_machine
+                .interpret(Cursor::new(vec![]), Vec::new())
+                .unwrap();
+        }
+
+        let mut expected = Vec::new();
+        for (step, head, before, after) in [(1, 0, 0, 1), (2, 0, 1, 1), (3, 1, 0, 255)] {
+            TraceRecord {
+                step,
+                program_counter: step as usize - 1,
+                instruction: program.commands()[step as usize - 1],
+                head,
+                before,
+                after,
+            }
+            .write_json(&mut expected)
+            .unwrap();
+        }
+
+        assert_eq!(
+            String::from_utf8(trace).unwrap(),
+            String::from_utf8(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn restored_snapshots_carry_on_running() {
+        let program = BFProgram::from_str("snapshot.bf", "++++++[>++++++++<-]>+.+.").unwrap();
+        let mut whole_run: BFVirtualMachine<u16> = BFVirtualMachine::new(&program, true, 2);
+        let mut expected = Vec::new();
+        whole_run
+            .interpret(Cursor::new(vec![]), &mut expected)
+            .unwrap();
+
+        let mut first_half: BFVirtualMachine<u16> = BFVirtualMachine::new(&program, true, 2);
+        let mut output = Vec::new();
+        first_half.run_until(&mut Cursor::new(vec![]), &mut output, |vm| vm.steps() == 20);
+        let mut bytes = Vec::new();
+        first_half.snapshot().write_to(&mut bytes).unwrap();
+
+        let mut second_half: BFVirtualMachine<u16> = BFVirtualMachine::new(&program, false, 1);
+        second_half
+            .restore(VmState::read_from(&mut &bytes[..]).unwrap())
+            .unwrap();
+        second_half
+            .interpret(Cursor::new(vec![]), &mut output)
+            .unwrap();
+
+        assert_eq!(output, expected);
+        assert_eq!(second_half.steps(), whole_run.steps());
+        assert_eq!(second_half.snapshot(), whole_run.snapshot());
+    }
+
+    #[test]
+    fn snapshots_work_as_fixtures() {
+        // Start part way through, with the loop about to finish
+        let program = BFProgram::from_str("fixture.bf", "+[-]>.").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        let fixture = VmState {
+            program_length: 6,
+            program_counter: 2,
+            steps: 2,
+            head: 0,
+            origin: 0,
+            cells: vec![1, b'A'],
+            boundary: TapeBoundary::Error,
+            growth: TapeGrowth::default(),
+            overflow: OverflowPolicy::Trap,
+            eof: EofBehavior::Zero,
+        };
+
+        virtual_machine.restore(fixture.clone()).unwrap();
+        let mut output = Vec::new();
+        virtual_machine
+            .interpret(Cursor::new(vec![]), &mut output)
+            .unwrap();
+        assert_eq!(output, b"A");
+        assert_eq!(virtual_machine.steps(), 6);
+
+        let other = BFProgram::from_str("other.bf", "+").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&other, false, 4);
+        assert!(matches!(
+            virtual_machine.restore(fixture.clone()),
+            Err(SnapshotError::ProgramMismatch)
+        ));
+        assert!(matches!(
+            virtual_machine.restore(VmState {
+                program_length: 1,
+                head: 2,
+                ..fixture
+            }),
+            Err(SnapshotError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn step_back_undoes_instructions() {
+        let program = BFProgram::from_str("back.bf", ",>++[-<+>]<.").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        virtual_machine.set_recording(Some(100));
+        let mut input = Cursor::new(b"A".to_vec());
+        let mut output = Vec::new();
+
+        let mut states = vec![virtual_machine.snapshot()];
+        loop {
+            let outcome = virtual_machine.step(&mut input, &mut output);
+            states.push(virtual_machine.snapshot());
+            if !matches!(outcome, StepOutcome::Continued) {
+                break;
+            }
+        }
+        assert_eq!(output, b"C");
+        assert_eq!(virtual_machine.recorded_steps(), states.len() - 1);
+
+        states.pop();
+        while let Some(state) = states.pop() {
+            let change = virtual_machine.step_back().unwrap();
+            assert_eq!(change.program_counter, state.program_counter);
+            assert_eq!(virtual_machine.snapshot(), state);
+        }
+        assert!(virtual_machine.step_back().is_none());
+
+        // The input read by `,` is read again, the reader has nothing left
+        virtual_machine.interpret(&mut input, &mut output).unwrap();
+        assert_eq!(output, b"CC");
+    }
+
+    #[test]
+    fn step_back_follows_a_growing_tape() {
+        let program = BFProgram::from_str("grow.bf", "<-<++>").unwrap();
+        let mut virtual_machine: BFVirtualMachine<i8> = BFVirtualMachine::new(&program, true, 1);
+        virtual_machine.set_tape_growth(TapeGrowth {
+            policy: GrowthPolicy::Chunk(1),
+            max_size: None,
+            bidirectional: true,
+        });
+        virtual_machine.set_recording(Some(3));
+        virtual_machine
+            .interpret(Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        // Only the last three instructions can be undone
+        assert!(virtual_machine.step_back_until(|vm| vm.program_counter() == 4));
+        assert_eq!(virtual_machine.tape().cells(), [1, -1, 0]);
+        assert_eq!(virtual_machine.tape_pointer(), 0);
+        assert!(!virtual_machine.step_back_until(|_| false));
+        assert_eq!(virtual_machine.program_counter(), 3);
+        assert_eq!(virtual_machine.tape().cells(), [0, -1, 0]);
+        assert_eq!(virtual_machine.steps(), 3);
+    }
+
+    #[test]
+    fn resume_waits_for_input() {
+        let program = BFProgram::from_str("echo.bf", "+[,.]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 1);
+        virtual_machine.set_eof_behavior(EofBehavior::Zero);
+
+        assert!(matches!(virtual_machine.resume(), RunState::NeedsInput));
+        virtual_machine.feed_input(b"hi");
+        assert!(matches!(virtual_machine.resume(), RunState::Output(bytes) if bytes == b"h"));
+        assert!(matches!(virtual_machine.resume(), RunState::Output(bytes) if bytes == b"i"));
+        assert!(matches!(virtual_machine.resume(), RunState::NeedsInput));
+        assert!(matches!(virtual_machine.resume(), RunState::NeedsInput));
+
+        virtual_machine.feed_input(b"!");
+        virtual_machine.close_input();
+        assert!(matches!(virtual_machine.resume(), RunState::Output(bytes) if bytes == b"!"));
+        assert!(matches!(virtual_machine.resume(), RunState::Output(bytes) if bytes == [0]));
+        assert!(matches!(virtual_machine.resume(), RunState::Halted));
+        assert!(matches!(virtual_machine.resume(), RunState::Halted));
+    }
+
+    #[test]
+    fn cancelled_programs_stop() {
+        let program = BFProgram::from_str("forever.bf", "+[]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 1);
+        let token = CancelToken::new();
+        virtual_machine.set_cancel_token(token.clone());
+
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            token.cancel();
+        });
+        let res = virtual_machine.interpret(Cursor::new(vec![]), Vec::new());
+        canceller.join().unwrap();
+
+        assert!(matches!(res, Err(VMError::Cancelled(_))));
+        assert!(virtual_machine.steps().is_multiple_of(1024));
+        assert_eq!(virtual_machine.program_counter(), 2);
+    }
+
+    #[test]
+    fn infinite_loops_are_found() {
+        let detect = |source: &str, input: &[u8]| {
+            let program = BFProgram::from_str("loop.bf", source).unwrap();
+            let mut virtual_machine: BFVirtualMachine<u8> =
+                BFVirtualMachine::new(&program, false, 4);
+            virtual_machine.set_loop_detection(true);
+            virtual_machine
+                .set_resource_limits(ResourceLimits {
+                    max_steps: Some(100_000),
+                    ..ResourceLimits::default()
+                })
+                .unwrap();
+            virtual_machine
+                .interpret(Cursor::new(input.to_vec()), Vec::new())
+                .map_err(|e| match e {
+                    VMError::InfiniteLoop(context) => Some(context.instruction.column_number()),
+                    _ => None,
+                })
+        };
+
+        assert_eq!(detect("+[]", b""), Err(Some(2)));
+        // The second cell wraps round, so this comes back to where it started
+        assert_eq!(detect("+[>+<]", b""), Err(Some(5)));
+        assert_eq!(detect(">+[<>]", b""), Err(Some(5)));
+        assert_eq!(detect("++++[>++++[>+<-]<-]>>.", b""), Ok(()));
+        // Reading input starts again, until the input runs out
+        assert_eq!(detect("+[,]", b"abc"), Err(Some(3)));
+        assert_eq!(detect(",[>,]", b"abc"), Ok(()));
+    }
 }
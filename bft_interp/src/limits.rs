@@ -0,0 +1,132 @@
+//! Limits on the resources a program may use
+//!
+//! These are for running programs which can not be trusted to finish.  The
+//! plain machine counts a step for every command it runs, the IR machine
+//! counts one for every operation, and one for every cell a scan moves
+//! over, so an optimised program gets further on the same step limit.
+//!
+//! The tape limit is kept by the tape, on top of the max_size in its
+//! `TapeGrowth`: the smaller of the two applies, the tape never allocates
+//! past it.  Hitting max_size fails with `VMError::TapeTooBig` and hitting
+//! max_tape with `VMError::TapeLimitExceeded`, if they are the same the
+//! limit wins.
+
+use crate::VMError;
+use bft_types::InputInstruction;
+use std::time::{Duration, Instant};
+
+/// How many steps to run between looks at the clock
+const STEPS_PER_TIME_CHECK: u64 = 1024;
+
+/// The most a program may do, None means there is no limit
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ResourceLimits {
+    /// The most instructions to run
+    pub max_steps: Option<u64>,
+    /// The longest to run for, timed from the first step
+    pub max_time: Option<Duration>,
+    /// The most bytes to output
+    pub max_output: Option<usize>,
+    /// The most cells the tape may grow to, the tape is never made bigger
+    /// than this
+    pub max_tape: Option<usize>,
+}
+
+/// What a program has used so far
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Usage {
+    pub(crate) steps: u64,
+    pub(crate) output: usize,
+    started: Option<Instant>,
+}
+
+impl ResourceLimits {
+    /// Count a step, failing if there are no steps left or the time is up.
+    /// The clock is only looked at every STEPS_PER_TIME_CHECK steps.
+    pub(crate) fn step(
+        &self,
+        usage: &mut Usage,
+        instruction: InputInstruction,
+    ) -> Result<(), VMError> {
+        if let Some(max_steps) = self.max_steps {
+            if usage.steps >= max_steps {
+                return Err(VMError::StepLimitExceeded(instruction.into()));
+            }
+        }
+
+        if let Some(max_time) = self.max_time {
+            let started = *usage.started.get_or_insert_with(Instant::now);
+            if usage.steps.is_multiple_of(STEPS_PER_TIME_CHECK) && started.elapsed() > max_time {
+                return Err(VMError::TimeLimitExceeded(instruction.into()));
+            }
+        }
+
+        usage.steps += 1;
+        Ok(())
+    }
+
+    /// Count bytes about to be output, failing if that would be too many
+    pub(crate) fn output(
+        &self,
+        usage: &mut Usage,
+        bytes: usize,
+        instruction: InputInstruction,
+    ) -> Result<(), VMError> {
+        match self.max_output {
+            Some(max_output) if usage.output + bytes > max_output => {
+                Err(VMError::OutputLimitExceeded(instruction.into()))
+            }
+            _ => {
+                usage.output += bytes;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ResourceLimits, Usage};
+    use crate::VMError;
+    use bft_types::{BFCommand, InputInstruction};
+    use std::time::Duration;
+
+    #[test]
+    fn limits_are_enforced() {
+        let instruction = InputInstruction::new(BFCommand::OutputByte, 0, 0);
+        let limits = ResourceLimits {
+            max_steps: Some(2),
+            max_time: None,
+            max_output: Some(3),
+            max_tape: Some(10),
+        };
+        let mut usage = Usage::default();
+
+        assert!(limits.step(&mut usage, instruction).is_ok());
+        assert!(limits.step(&mut usage, instruction).is_ok());
+        assert!(matches!(
+            limits.step(&mut usage, instruction),
+            Err(VMError::StepLimitExceeded(_))
+        ));
+
+        assert!(limits.output(&mut usage, 3, instruction).is_ok());
+        assert!(matches!(
+            limits.output(&mut usage, 1, instruction),
+            Err(VMError::OutputLimitExceeded(_))
+        ));
+
+        let limits = ResourceLimits {
+            max_time: Some(Duration::from_millis(50)),
+            ..ResourceLimits::default()
+        };
+        let mut usage = Usage::default();
+        assert!(limits.step(&mut usage, instruction).is_ok());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limits.step(&mut usage, instruction).is_ok());
+        usage.steps = 1024;
+        assert!(matches!(
+            limits.step(&mut usage, instruction),
+            Err(VMError::TimeLimitExceeded(_))
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+//! Watching what the virtual machine does
+//!
+//! A `VmObserver` is told about every instruction `BFVirtualMachine::step`
+//! runs, so that tools such as tracers and profilers can be built without
+//! changing the machine.  Every method does nothing by default, so an
+//! observer only implements the events it wants.  When no observers are
+//! added the machine skips all of this.
+//!
+//! For each instruction `on_step` is called before it runs, then the events
+//! for what it did, or `on_error` if it failed.  That includes an
+//! instruction stopped by cancelling or a resource limit before it could
+//! run.  Cell indexes are positions on the tape after the instruction ran,
+//! so they are not upset by the tape growing to the left.
+
+use crate::VMError;
+use bft_types::InputInstruction;
+use std::fmt;
+use std::io::Write;
+
+/// The events of a running virtual machine
+pub trait VmObserver<T> {
+    /// The instruction at program_counter is about to run
+    fn on_step(&mut self, _program_counter: usize, _instruction: &InputInstruction) {}
+
+    /// The cell at index changed from before to after
+    fn on_cell_change(&mut self, _index: usize, _before: T, _after: T) {}
+
+    /// The head moved from one cell to another
+    fn on_head_move(&mut self, _from: usize, _to: usize) {}
+
+    /// A `,` read byte, None at the end of the input
+    fn on_input(&mut self, _byte: Option<u8>) {}
+
+    /// A `.` wrote bytes
+    fn on_output(&mut self, _bytes: &[u8]) {}
+
+    /// A `[` found a nonzero cell and started running the loop body
+    fn on_loop_enter(&mut self, _instruction: &InputInstruction) {}
+
+    /// A `]` found a zero cell, so the loop has finished
+    fn on_loop_exit(&mut self, _instruction: &InputInstruction) {}
+
+    /// The instruction failed
+    fn on_error(&mut self, _error: &VMError) {}
+}
+
+/// Lending an observer lets the caller look at it once the machine is done
+impl<T, O> VmObserver<T> for &mut O
+where
+    O: VmObserver<T> + ?Sized,
+{
+    fn on_step(&mut self, program_counter: usize, instruction: &InputInstruction) {
+        (**self).on_step(program_counter, instruction)
+    }
+
+    fn on_cell_change(&mut self, index: usize, before: T, after: T) {
+        (**self).on_cell_change(index, before, after)
+    }
+
+    fn on_head_move(&mut self, from: usize, to: usize) {
+        (**self).on_head_move(from, to)
+    }
+
+    fn on_input(&mut self, byte: Option<u8>) {
+        (**self).on_input(byte)
+    }
+
+    fn on_output(&mut self, bytes: &[u8]) {
+        (**self).on_output(bytes)
+    }
+
+    fn on_loop_enter(&mut self, instruction: &InputInstruction) {
+        (**self).on_loop_enter(instruction)
+    }
+
+    fn on_loop_exit(&mut self, instruction: &InputInstruction) {
+        (**self).on_loop_exit(instruction)
+    }
+
+    fn on_error(&mut self, error: &VMError) {
+        (**self).on_error(error)
+    }
+}
+
+/// The observers added to a virtual machine
+pub(crate) struct Observers<'a, T>(pub(crate) Vec<Box<dyn VmObserver<T> + 'a>>);
+
+impl<T> fmt::Debug for Observers<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
+
+/// An observer which writes a line for every event.  Logging is only for
+/// looking at, so errors writing the log are ignored.
+#[derive(Debug)]
+pub struct Logger<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Logger<W> {
+    pub fn new(out: W) -> Self {
+        Logger { out }
+    }
+
+    // Just write the message directly to the given output with a newline.
+    pub fn log(&mut self, message: &str) {
+        let _ = writeln!(self.out, "{}", message);
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+impl<T, W> VmObserver<T> for Logger<W>
+where
+    T: std::fmt::Display,
+    W: Write,
+{
+    fn on_step(&mut self, program_counter: usize, instruction: &InputInstruction) {
+        self.log(&format!("step {} {}", program_counter, instruction));
+    }
+
+    fn on_cell_change(&mut self, index: usize, before: T, after: T) {
+        self.log(&format!("cell #{} {} -> {}", index, before, after));
+    }
+
+    fn on_head_move(&mut self, from: usize, to: usize) {
+        self.log(&format!("head #{} -> #{}", from, to));
+    }
+
+    fn on_input(&mut self, byte: Option<u8>) {
+        match byte {
+            Some(byte) => self.log(&format!("input {}", byte)),
+            None => self.log("input EOF"),
+        }
+    }
+
+    fn on_output(&mut self, bytes: &[u8]) {
+        self.log(&format!("output {:?}", bytes));
+    }
+
+    fn on_loop_enter(&mut self, instruction: &InputInstruction) {
+        self.log(&format!("enter loop {}", instruction));
+    }
+
+    fn on_loop_exit(&mut self, instruction: &InputInstruction) {
+        self.log(&format!("exit loop {}", instruction));
+    }
+
+    fn on_error(&mut self, error: &VMError) {
+        self.log(&format!("error {}", error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Logger;
+    use crate::limits::ResourceLimits;
+    use crate::BFVirtualMachine;
+    use bft_types::BFProgram;
+    use std::io::Cursor;
+
+    #[test]
+    fn logger_sees_every_event() {
+        let program = BFProgram::from_str("observe.bf", ",[-]>.<<").unwrap();
+        let mut log = Vec::new();
+        {
+            let mut logger = Logger::new(&mut log);
+            let mut virtual_machine: BFVirtualMachine<u8> =
+                BFVirtualMachine::new(&program, false, 2);
+            virtual_machine.add_observer(&mut logger);
+            assert!(virtual_machine
+                .interpret(Cursor::new(vec![1]), Vec::new())
+                .is_err());
+        }
+
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "step 0 [Line 0, Col 0] ,\ninput 1\ncell #0 0 -> 1\n\
+             step 1 [Line 0, Col 1] [\nenter loop [Line 0, Col 1] [\n\
+             step 2 [Line 0, Col 2] -\ncell #0 1 -> 0\n\
+             step 3 [Line 0, Col 3] ]\nexit loop [Line 0, Col 3] ]\n\
+             step 4 [Line 0, Col 4] >\nhead #0 -> #1\n\
+             step 5 [Line 0, Col 5] .\noutput [0]\n\
+             step 6 [Line 0, Col 6] <\nhead #1 -> #0\n\
+             step 7 [Line 0, Col 7] <\n\
+             error [Line 0, Col 7] <: the head moved off the end of the tape \
+             (head at #0, cell 0, after 8 steps)\n"
+        );
+    }
+
+    #[test]
+    fn logger_sees_instructions_stopped_by_limits() {
+        let program = BFProgram::from_str("observe.bf", "++").unwrap();
+        let mut log = Vec::new();
+        {
+            let mut logger = Logger::new(&mut log);
+            let mut virtual_machine: BFVirtualMachine<u8> =
+                BFVirtualMachine::new(&program, false, 2);
+            virtual_machine
+                .set_resource_limits(ResourceLimits {
+                    max_steps: Some(1),
+                    ..ResourceLimits::default()
+                })
+                .unwrap();
+            virtual_machine.add_observer(&mut logger);
+            assert!(virtual_machine
+                .interpret(Cursor::new(vec![]), Vec::new())
+                .is_err());
+        }
+
+        assert_eq!(
+            String::from_utf8(log).unwrap(),
+            "step 0 [Line 0, Col 0] +\ncell #0 0 -> 1\n\
+             step 1 [Line 0, Col 1] +\n\
+             error [Line 0, Col 1] +: the step limit was reached \
+             (head at #0, cell 1, after 1 steps)\n"
+        );
+    }
+}
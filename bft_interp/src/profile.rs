@@ -0,0 +1,230 @@
+//! Counting how often each instruction of a program runs
+//!
+//! A `Profile` is filled in by running a `BFVirtualMachine` with
+//! `Profile::run`, which drives the machine one step at a time.  The counts
+//! can also be written as an lcov coverage report, which numbers lines from
+//! one rather than from zero.
+
+use crate::{BFVirtualMachine, CellKind, StepOutcome, VMError};
+use bft_types::{BFCommand, BFProgram, InputInstruction};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+/// How much time was spent in a loop
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoopProfile {
+    /// The `[` which starts the loop
+    pub start: InputInstruction,
+    /// The `]` which ends the loop
+    pub end: InputInstruction,
+    /// How many times the loop was reached
+    pub entries: u64,
+    /// How many times the body of the loop ran
+    pub iterations: u64,
+    /// How many instructions ran inside the loop, including its brackets
+    pub instructions: u64,
+}
+
+/// The number of times each instruction of a program ran
+#[derive(Debug, Clone)]
+pub struct Profile<'a> {
+    program: &'a BFProgram,
+    hits: Vec<u64>,
+}
+
+impl<'a> Profile<'a> {
+    /// Create an empty profile for the program
+    pub fn new(program: &'a BFProgram) -> Profile<'a> {
+        Profile {
+            program,
+            hits: vec![0; program.commands().len()],
+        }
+    }
+
+    /// Run the machine to the end, counting every instruction it runs.
+    /// The machine must be running the profile's program.
+    pub fn run<T, R, W>(
+        &mut self,
+        virtual_machine: &mut BFVirtualMachine<'_, T>,
+        mut input: R,
+        mut output: W,
+    ) -> Result<(), VMError>
+    where
+        T: CellKind,
+        R: Read,
+        W: Write,
+    {
+        loop {
+            let program_counter = virtual_machine.program_counter();
+
+            match virtual_machine.step(&mut input, &mut output) {
+                StepOutcome::Continued | StepOutcome::Watchpoint { .. } => {
+                    self.record(program_counter)
+                }
+                // Halting before running anything leaves the program counter
+                // past the end
+                StepOutcome::Halted => {
+                    if program_counter < self.hits.len() {
+                        self.record(program_counter);
+                    }
+                    return Ok(());
+                }
+                StepOutcome::Error(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Count a run of the instruction at index
+    pub fn record(&mut self, index: usize) {
+        self.hits[index] += 1;
+    }
+
+    /// Return the program which was profiled
+    pub fn program(&self) -> &'a BFProgram {
+        self.program
+    }
+
+    /// Return the number of times each instruction ran, by index
+    pub fn hits(&self) -> &[u64] {
+        &self.hits
+    }
+
+    /// Return the total number of instructions run on each line which has
+    /// any instructions on it
+    pub fn line_hits(&self) -> BTreeMap<usize, u64> {
+        let mut lines = BTreeMap::new();
+
+        for (instruction, hits) in self.program.commands().iter().zip(&self.hits) {
+            *lines.entry(instruction.line_number()).or_insert(0) += hits;
+        }
+
+        lines
+    }
+
+    /// Return every loop in the program, in source order
+    pub fn loops(&self) -> Vec<LoopProfile> {
+        self.loop_brackets()
+            .map(|(start, end)| self.loop_profile(start, end))
+            .collect()
+    }
+
+    /// Return the loops whose body never ran, either because they were
+    /// never reached or because the cell was always zero when they were
+    pub fn never_run_loops(&self) -> Vec<LoopProfile> {
+        // The instruction after a `[` is the first of its body, or its `]`
+        // for an empty loop, and it is only run by running the loop
+        self.loop_brackets()
+            .filter(|&(start, _)| self.hits[start + 1] == 0)
+            .map(|(start, end)| self.loop_profile(start, end))
+            .collect()
+    }
+
+    /// Return the indexes of the brackets of every loop
+    fn loop_brackets(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.program
+            .commands()
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| instruction.get_command() == BFCommand::IfZeroJumpForward)
+            .filter_map(move |(start, _)| Some((start, self.program.matching_bracket(start)?)))
+    }
+
+    fn loop_profile(&self, start: usize, end: usize) -> LoopProfile {
+        let commands = self.program.commands();
+
+        LoopProfile {
+            start: commands[start],
+            end: commands[end],
+            entries: self.hits[start],
+            iterations: self.hits[end],
+            instructions: self.hits[start..=end].iter().sum(),
+        }
+    }
+
+    /// Write an lcov report of which lines ran, keyed by the program's
+    /// filename
+    pub fn write_lcov(&self, out: &mut impl Write) -> io::Result<()> {
+        let line_hits = self.line_hits();
+
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", self.program.filename().display())?;
+        for (line_number, hits) in &line_hits {
+            writeln!(out, "DA:{},{}", line_number + 1, hits)?;
+        }
+        writeln!(out, "LF:{}", line_hits.len())?;
+        writeln!(
+            out,
+            "LH:{}",
+            line_hits.values().filter(|&&hits| hits > 0).count()
+        )?;
+        writeln!(out, "end_of_record")
+    }
+
+    /// Return up to count loops, the ones which ran the most instructions
+    /// first
+    pub fn hottest_loops(&self, count: usize) -> Vec<LoopProfile> {
+        let mut loops = self.loops();
+        loops.sort_by_key(|hot_loop| std::cmp::Reverse(hot_loop.instructions));
+        loops.truncate(count);
+        loops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Profile;
+    use crate::BFVirtualMachine;
+    use bft_types::BFProgram;
+    use std::io::Cursor;
+
+    #[test]
+    fn counts_every_instruction() {
+        let program = BFProgram::from_str("profile.bf", "++[>+++[>+<-]<-]\n>>.").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        let mut profile = Profile::new(&program);
+
+        profile
+            .run(&mut virtual_machine, Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        assert_eq!(profile.hits()[..3], [1, 1, 1]);
+        assert_eq!(profile.hits()[8], 6);
+        assert_eq!(profile.hits()[18], 1);
+        assert_eq!(profile.line_hits()[&1], 3);
+
+        // The instructions in a loop include those in the loops inside it
+        let loops = profile.hottest_loops(5);
+        assert_eq!(loops.len(), 2);
+        assert_eq!(loops[0].start.column_number(), 2);
+        assert_eq!((loops[0].entries, loops[0].iterations), (1, 2));
+        assert_eq!(loops[0].instructions, 1 + 2 * 7 + 32);
+        assert_eq!(loops[1].start.column_number(), 7);
+        assert_eq!((loops[1].entries, loops[1].iterations), (2, 6));
+        assert_eq!(loops[1].instructions, 2 + 6 * 5);
+    }
+
+    #[test]
+    fn coverage_finds_loops_which_never_ran() {
+        let source = "+[-]\n[>]\n+[[-]]\n>[\n+\n]";
+        let program = BFProgram::from_str("coverage.bf", source).unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        let mut profile = Profile::new(&program);
+        profile
+            .run(&mut virtual_machine, Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        let never_run: Vec<(usize, u64)> = profile
+            .never_run_loops()
+            .iter()
+            .map(|l| (l.start.line_number(), l.entries))
+            .collect();
+        assert_eq!(never_run, [(1, 1), (3, 1)]);
+
+        let mut lcov = Vec::new();
+        profile.write_lcov(&mut lcov).unwrap();
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "TN:\nSF:coverage.bf\nDA:1,4\nDA:2,1\nDA:3,6\nDA:4,2\nDA:5,0\nDA:6,0\nLF:6\nLH:4\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,362 @@
+//! Saving the state of a virtual machine and loading it back
+//!
+//! A `VmState` holds everything needed to carry on running a program from
+//! where it was, apart from the program itself and its input and output.
+//! It can be written in a compact binary format, all numbers little-endian:
+//!
+//! ```text
+//! magic           4 bytes  "BFTS"
+//! version         u8       1
+//! cell type       u8 length then the name, such as "u8" or "i32"
+//! program length  u64      the number of commands, to catch the wrong program
+//! program counter u64
+//! steps           u64
+//! head            u64
+//! origin          u64
+//! boundary        u8       0 error, 1 wrap, 2 clamp, 3 grow
+//! growth policy   u8       0 double, 1 chunk, followed by the chunk as a u64
+//! max size        u8       0 none, 1 followed by the size as a u64
+//! bidirectional   u8
+//! overflow        u8       0 wrap, 1 saturate, 2 trap
+//! eof             u8       0 unchanged, 1 zero, 2 minus one
+//! cell count      u64
+//! cells           cell count cells of BITS / 8 bytes each
+//! ```
+
+use crate::tape::{GrowthPolicy, TapeBoundary, TapeGrowth};
+use crate::{CellKind, EofBehavior, OverflowPolicy};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"BFTS";
+const VERSION: u8 = 1;
+
+/// Errors from reading or restoring a VmState
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The data does not start with the magic bytes
+    NotASnapshot,
+    UnsupportedVersion(u8),
+    /// The snapshot is of a machine with a different type of cell
+    CellTypeMismatch {
+        expected: &'static str,
+        found: String,
+    },
+    /// The snapshot is of a machine running a program of a different length
+    ProgramMismatch,
+    /// A value in the snapshot is not valid
+    Corrupt(&'static str),
+    /// The snapshot's tape is bigger than the machine's tape limit
+    TapeLimitExceeded,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "unable to read snapshot: {}", e),
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::CellTypeMismatch { expected, found } => write!(
+                f,
+                "snapshot has {} cells, the machine has {} cells",
+                found, expected
+            ),
+            SnapshotError::ProgramMismatch => write!(f, "snapshot is of a different program"),
+            SnapshotError::Corrupt(what) => write!(f, "snapshot has an invalid {}", what),
+            SnapshotError::TapeLimitExceeded => {
+                write!(f, "snapshot's tape is bigger than the tape limit")
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// The state of a BFVirtualMachine
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmState<T> {
+    /// The number of commands in the program, restore checks this
+    pub program_length: usize,
+    pub program_counter: usize,
+    /// The number of instructions run
+    pub steps: u64,
+    /// The index of the cell under the head
+    pub head: usize,
+    /// The index of the first cell of the original tape
+    pub origin: usize,
+    pub cells: Vec<T>,
+    pub boundary: TapeBoundary,
+    pub growth: TapeGrowth,
+    pub overflow: OverflowPolicy,
+    pub eof: EofBehavior,
+}
+
+impl<T> VmState<T>
+where
+    T: CellKind,
+{
+    /// Write the state in the snapshot format
+    pub fn write_to(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        write_u8(out, VERSION)?;
+        write_u8(out, T::NAME.len() as u8)?;
+        out.write_all(T::NAME.as_bytes())?;
+
+        write_u64(out, self.program_length as u64)?;
+        write_u64(out, self.program_counter as u64)?;
+        write_u64(out, self.steps)?;
+        write_u64(out, self.head as u64)?;
+        write_u64(out, self.origin as u64)?;
+
+        write_u8(
+            out,
+            match self.boundary {
+                TapeBoundary::Error => 0,
+                TapeBoundary::Wrap => 1,
+                TapeBoundary::Clamp => 2,
+                TapeBoundary::Grow => 3,
+            },
+        )?;
+        match self.growth.policy {
+            GrowthPolicy::Double => write_u8(out, 0)?,
+            GrowthPolicy::Chunk(cells) => {
+                write_u8(out, 1)?;
+                write_u64(out, cells as u64)?;
+            }
+        }
+        match self.growth.max_size {
+            None => write_u8(out, 0)?,
+            Some(max_size) => {
+                write_u8(out, 1)?;
+                write_u64(out, max_size as u64)?;
+            }
+        }
+        write_u8(out, self.growth.bidirectional as u8)?;
+        write_u8(
+            out,
+            match self.overflow {
+                OverflowPolicy::Wrap => 0,
+                OverflowPolicy::Saturate => 1,
+                OverflowPolicy::Trap => 2,
+            },
+        )?;
+        write_u8(
+            out,
+            match self.eof {
+                EofBehavior::Unchanged => 0,
+                EofBehavior::Zero => 1,
+                EofBehavior::MinusOne => 2,
+            },
+        )?;
+
+        write_u64(out, self.cells.len() as u64)?;
+        let width = (T::BITS / 8) as usize;
+        for cell in &self.cells {
+            out.write_all(&(cell.to_i128() as u128).to_le_bytes()[..width])?;
+        }
+
+        Ok(())
+    }
+
+    /// Read a state written by write_to, which must have cells of type T
+    pub fn read_from(reader: &mut impl Read) -> Result<VmState<T>, SnapshotError> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+
+        let version = read_u8(reader)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let mut name = vec![0u8; read_u8(reader)? as usize];
+        reader.read_exact(&mut name)?;
+        if name != T::NAME.as_bytes() {
+            return Err(SnapshotError::CellTypeMismatch {
+                expected: T::NAME,
+                found: String::from_utf8_lossy(&name).into_owned(),
+            });
+        }
+
+        let program_length = read_usize(reader)?;
+        let program_counter = read_usize(reader)?;
+        let steps = read_u64(reader)?;
+        let head = read_usize(reader)?;
+        let origin = read_usize(reader)?;
+
+        let boundary = match read_u8(reader)? {
+            0 => TapeBoundary::Error,
+            1 => TapeBoundary::Wrap,
+            2 => TapeBoundary::Clamp,
+            3 => TapeBoundary::Grow,
+            _ => return Err(SnapshotError::Corrupt("tape boundary")),
+        };
+        let policy = match read_u8(reader)? {
+            0 => GrowthPolicy::Double,
+            1 => GrowthPolicy::Chunk(read_usize(reader)?),
+            _ => return Err(SnapshotError::Corrupt("growth policy")),
+        };
+        let max_size = match read_u8(reader)? {
+            0 => None,
+            1 => Some(read_usize(reader)?),
+            _ => return Err(SnapshotError::Corrupt("maximum tape size")),
+        };
+        let bidirectional = match read_u8(reader)? {
+            0 => false,
+            1 => true,
+            _ => return Err(SnapshotError::Corrupt("growth direction")),
+        };
+        let overflow = match read_u8(reader)? {
+            0 => OverflowPolicy::Wrap,
+            1 => OverflowPolicy::Saturate,
+            2 => OverflowPolicy::Trap,
+            _ => return Err(SnapshotError::Corrupt("overflow policy")),
+        };
+        let eof = match read_u8(reader)? {
+            0 => EofBehavior::Unchanged,
+            1 => EofBehavior::Zero,
+            2 => EofBehavior::MinusOne,
+            _ => return Err(SnapshotError::Corrupt("EOF behaviour")),
+        };
+
+        let count = read_usize(reader)?;
+        if head >= count || origin >= count {
+            return Err(SnapshotError::Corrupt("head position"));
+        }
+
+        // Read the cells one at a time rather than allocating them all, so a
+        // corrupt count can not allocate a huge tape before the data runs out
+        let width = (T::BITS / 8) as usize;
+        let mut cells = Vec::new();
+        let mut bytes = vec![0u8; width];
+        for _ in 0..count {
+            reader.read_exact(&mut bytes)?;
+            let mut value = [0u8; 16];
+            value[..width].copy_from_slice(&bytes);
+            cells.push(T::from_i128(u128::from_le_bytes(value) as i128));
+        }
+
+        Ok(VmState {
+            program_length,
+            program_counter,
+            steps,
+            head,
+            origin,
+            cells,
+            boundary,
+            growth: TapeGrowth {
+                policy,
+                max_size,
+                bidirectional,
+            },
+            overflow,
+            eof,
+        })
+    }
+}
+
+fn write_u8(out: &mut impl Write, value: u8) -> io::Result<()> {
+    out.write_all(&[value])
+}
+
+fn write_u64(out: &mut impl Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut buffer = [0u8; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buffer = [0u8; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+fn read_usize(reader: &mut impl Read) -> Result<usize, SnapshotError> {
+    let value = read_u64(reader)?;
+    usize::try_from(value).map_err(|_| SnapshotError::Corrupt("size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SnapshotError, VmState};
+    use crate::tape::{GrowthPolicy, TapeBoundary, TapeGrowth};
+    use crate::{EofBehavior, OverflowPolicy};
+
+    fn state() -> VmState<i32> {
+        VmState {
+            program_length: 10,
+            program_counter: 4,
+            steps: 1234,
+            head: 2,
+            origin: 1,
+            cells: vec![0, -1, 70000, i32::MIN],
+            boundary: TapeBoundary::Grow,
+            growth: TapeGrowth {
+                policy: GrowthPolicy::Chunk(16),
+                max_size: Some(100),
+                bidirectional: true,
+            },
+            overflow: OverflowPolicy::Trap,
+            eof: EofBehavior::MinusOne,
+        }
+    }
+
+    #[test]
+    fn states_survive_a_round_trip() {
+        let mut bytes = Vec::new();
+        state().write_to(&mut bytes).unwrap();
+
+        assert_eq!(&bytes[..9], b"BFTS\x01\x03i32");
+        assert_eq!(bytes.len(), 9 + 5 * 8 + 1 + 9 + 9 + 3 + 8 + 4 * 4);
+        assert_eq!(VmState::read_from(&mut &bytes[..]).unwrap(), state());
+    }
+
+    #[test]
+    fn bad_snapshots_are_rejected() {
+        let mut bytes = Vec::new();
+        state().write_to(&mut bytes).unwrap();
+
+        assert!(matches!(
+            VmState::<u8>::read_from(&mut &bytes[..]),
+            Err(SnapshotError::CellTypeMismatch { expected: "u8", .. })
+        ));
+        assert!(matches!(
+            VmState::<i32>::read_from(&mut &bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Io(_))
+        ));
+        assert!(matches!(
+            VmState::<i32>::read_from(&mut &b"BFTX"[..]),
+            Err(SnapshotError::NotASnapshot)
+        ));
+
+        bytes[4] = 2;
+        assert!(matches!(
+            VmState::<i32>::read_from(&mut &bytes[..]),
+            Err(SnapshotError::UnsupportedVersion(2))
+        ));
+    }
+}
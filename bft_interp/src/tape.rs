@@ -0,0 +1,446 @@
+//! The tape of cells which BrainFuck programs work on
+//!
+//! What happens when the head runs off either end of the tape depends on the
+//! tape's `TapeBoundary`.  A growing tape is extended whenever the head runs
+//! off its right hand end, and off its left hand end too if growth is
+//! bidirectional.  Cells added on the left move every existing cell along,
+//! `origin` says where the first cell of the original tape has ended up.
+
+use std::fmt;
+use std::io::{self, Write};
+
+/// What to do when the head moves off the end of the tape
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TapeBoundary {
+    /// Moving off the end is an error
+    #[default]
+    Error,
+    /// The tape is circular, moving off one end arrives at the other
+    Wrap,
+    /// The head stays on the cell at the end
+    Clamp,
+    /// The tape grows, see `TapeGrowth`
+    Grow,
+}
+
+/// How a growable tape is extended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GrowthPolicy {
+    /// Double the number of cells
+    Double,
+    /// Add a fixed number of cells
+    Chunk(usize),
+}
+
+/// The settings for a growable tape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TapeGrowth {
+    /// How many cells to add each time the tape grows
+    pub policy: GrowthPolicy,
+    /// The most cells the tape may grow to, None for no limit.  A smaller
+    /// limit set with `Tape::set_limit` takes its place.
+    pub max_size: Option<usize>,
+    /// Whether the tape also grows off its left hand end
+    pub bidirectional: bool,
+}
+
+impl Default for TapeGrowth {
+    fn default() -> Self {
+        TapeGrowth {
+            policy: GrowthPolicy::Double,
+            max_size: None,
+            bidirectional: false,
+        }
+    }
+}
+
+/// Errors from moving the head of a tape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TapeError {
+    /// The head would move off the end of a tape with the Error boundary,
+    /// or off the left hand end of a tape which only grows to the right
+    HeadOutOfBounds,
+    /// The tape would need to grow past its maximum size
+    TooBig,
+    /// The tape would need to grow past the limit set with set_limit, or
+    /// was already bigger than it
+    LimitExceeded,
+}
+
+impl fmt::Display for TapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TapeError::HeadOutOfBounds => write!(f, "head moved off the end of the tape"),
+            TapeError::TooBig => write!(f, "tape grew past its maximum size"),
+            TapeError::LimitExceeded => write!(f, "tape grew past its limit"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Tape<T> {
+    cells: Vec<T>,
+    head: usize,
+    origin: usize,
+    boundary: TapeBoundary,
+    growth: TapeGrowth,
+    limit: Option<usize>,
+}
+
+impl<T> Tape<T>
+where
+    T: Default + Copy,
+{
+    /// Create a new tape of size cells, all set to the default value
+    pub fn new(size: usize, boundary: TapeBoundary) -> Tape<T> {
+        Tape {
+            cells: vec![T::default(); size.max(1)],
+            head: 0,
+            origin: 0,
+            boundary,
+            growth: TapeGrowth::default(),
+            limit: None,
+        }
+    }
+
+    /// Rebuild a tape from its parts, the head and origin must be on it
+    pub(crate) fn from_parts(
+        cells: Vec<T>,
+        head: usize,
+        origin: usize,
+        boundary: TapeBoundary,
+        growth: TapeGrowth,
+    ) -> Tape<T> {
+        Tape {
+            cells,
+            head,
+            origin,
+            boundary,
+            growth,
+            limit: None,
+        }
+    }
+
+    /// Change what happens when the head moves off the end of the tape
+    pub fn set_boundary(&mut self, boundary: TapeBoundary) {
+        self.boundary = boundary;
+    }
+
+    /// Return the boundary policy
+    pub fn boundary(&self) -> TapeBoundary {
+        self.boundary
+    }
+
+    /// Change how the tape grows, this has no effect unless the boundary
+    /// is Grow
+    pub fn set_growth(&mut self, growth: TapeGrowth) {
+        self.growth = growth;
+    }
+
+    /// Return the growth settings
+    pub fn growth(&self) -> TapeGrowth {
+        self.growth
+    }
+
+    /// Cap the number of cells the tape may grow to, on top of the max_size
+    /// in its growth settings.  The tape never allocates past the limit, so
+    /// this fails, leaving the limit as it was, if the tape is already bigger.
+    pub fn set_limit(&mut self, limit: Option<usize>) -> Result<(), TapeError> {
+        match limit {
+            Some(limit) if self.cells.len() > limit => Err(TapeError::LimitExceeded),
+            _ => {
+                self.limit = limit;
+                Ok(())
+            }
+        }
+    }
+
+    /// Return whether the tape can grow
+    pub fn can_grow(&self) -> bool {
+        self.boundary == TapeBoundary::Grow
+    }
+
+    /// Return the index of the cell under the head
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    /// Put the head on the cell at index, which must be on the tape
+    pub(crate) fn set_head(&mut self, index: usize) {
+        self.head = index;
+    }
+
+    /// Return the index of the first cell of the original tape
+    pub fn origin(&self) -> usize {
+        self.origin
+    }
+
+    /// Return the number of cells
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    /// Return whether the tape has no cells, which is never true
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    /// Return all of the cells
+    pub fn cells(&self) -> &[T] {
+        &self.cells
+    }
+
+    /// Return the cell under the head
+    pub fn current(&self) -> T {
+        self.cells[self.head]
+    }
+
+    /// Set the cell under the head
+    pub fn set_current(&mut self, value: T) {
+        self.cells[self.head] = value;
+    }
+
+    /// Return the cell at index
+    pub fn cell(&self, index: usize) -> T {
+        self.cells[index]
+    }
+
+    /// Set the cell at index
+    pub fn set_cell(&mut self, index: usize, value: T) {
+        self.cells[index] = value;
+    }
+
+    /// Move the head one cell to the left
+    pub fn move_left(&mut self) -> Result<(), TapeError> {
+        self.move_by(-1)
+    }
+
+    /// Move the head one cell to the right
+    pub fn move_right(&mut self) -> Result<(), TapeError> {
+        self.move_by(1)
+    }
+
+    /// Move the head by offset cells, growing the tape if needed
+    pub fn move_by(&mut self, offset: isize) -> Result<(), TapeError> {
+        self.head = self.index_at(offset)?;
+        Ok(())
+    }
+
+    /// Return the index of the cell offset cells from the head, applying the
+    /// boundary policy if that is off the end of the tape
+    pub fn index_at(&mut self, offset: isize) -> Result<usize, TapeError> {
+        let index = self.head as isize + offset;
+        let len = self.cells.len() as isize;
+
+        if index >= 0 && index < len {
+            return Ok(index as usize);
+        }
+
+        match self.boundary {
+            TapeBoundary::Error => Err(TapeError::HeadOutOfBounds),
+            TapeBoundary::Wrap => Ok(index.rem_euclid(len) as usize),
+            TapeBoundary::Clamp => Ok(index.clamp(0, len - 1) as usize),
+            TapeBoundary::Grow => self.grow_to(index),
+        }
+    }
+
+    /// Grow the tape until index, which may be negative, is on it
+    fn grow_to(&mut self, mut index: isize) -> Result<usize, TapeError> {
+        while index < 0 {
+            if !self.growth.bidirectional {
+                return Err(TapeError::HeadOutOfBounds);
+            }
+            let added = self.grow_left()?;
+            index += added as isize;
+        }
+
+        while index as usize >= self.cells.len() {
+            self.grow_right()?;
+        }
+
+        Ok(index as usize)
+    }
+
+    /// Work out how many cells to add to the tape, never taking it past the
+    /// smaller of max_size and the limit
+    fn growth_amount(&self) -> Result<usize, TapeError> {
+        let amount = match self.growth.policy {
+            GrowthPolicy::Double => self.cells.len(),
+            GrowthPolicy::Chunk(n) => n,
+        }
+        .max(1);
+
+        let (max_size, error) = match (self.growth.max_size, self.limit) {
+            (Some(max_size), Some(limit)) if max_size < limit => (max_size, TapeError::TooBig),
+            (_, Some(limit)) => (limit, TapeError::LimitExceeded),
+            (Some(max_size), None) => (max_size, TapeError::TooBig),
+            (None, None) => return Ok(amount),
+        };
+
+        if self.cells.len() >= max_size {
+            Err(error)
+        } else {
+            Ok(amount.min(max_size - self.cells.len()))
+        }
+    }
+
+    fn grow_right(&mut self) -> Result<usize, TapeError> {
+        let amount = self.growth_amount()?;
+        self.cells.extend(std::iter::repeat_n(T::default(), amount));
+        Ok(amount)
+    }
+
+    fn grow_left(&mut self) -> Result<usize, TapeError> {
+        let amount = self.growth_amount()?;
+        let mut cells = vec![T::default(); amount];
+        cells.append(&mut self.cells);
+        self.cells = cells;
+        self.head += amount;
+        self.origin += amount;
+        Ok(amount)
+    }
+}
+
+impl<T> Tape<T>
+where
+    T: Default + Copy + fmt::Display,
+{
+    /// Write the cells up to window cells either side of the head on one
+    /// line, with the cell under the head in brackets
+    pub fn write_window(&self, out: &mut impl Write, window: usize) -> io::Result<()> {
+        let start = self.head.saturating_sub(window);
+        let end = self
+            .head
+            .saturating_add(window)
+            .saturating_add(1)
+            .min(self.cells.len());
+
+        for (index, cell) in (start..end).zip(&self.cells[start..end]) {
+            if index == self.head {
+                write!(out, "[#{}={}] ", index, cell)?;
+            } else {
+                write!(out, "#{}={} ", index, cell)?;
+            }
+        }
+
+        writeln!(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GrowthPolicy, Tape, TapeBoundary, TapeError, TapeGrowth};
+
+    #[test]
+    fn fixed_tape_does_not_grow() {
+        let mut tape: Tape<u8> = Tape::new(2, TapeBoundary::Error);
+
+        assert_eq!(tape.move_right(), Ok(()));
+        assert_eq!(tape.move_right(), Err(TapeError::HeadOutOfBounds));
+        assert_eq!(tape.head(), 1);
+        assert_eq!(tape.len(), 2);
+    }
+
+    #[test]
+    fn tape_grows_right() {
+        let mut tape: Tape<u8> = Tape::new(2, TapeBoundary::Grow);
+        tape.set_current(7);
+
+        for _ in 0..4 {
+            tape.move_right().unwrap();
+        }
+
+        assert_eq!(tape.head(), 4);
+        assert_eq!(tape.len(), 8);
+        assert_eq!(tape.cell(0), 7);
+        assert_eq!(tape.move_left(), Ok(()));
+    }
+
+    #[test]
+    fn tape_grows_left_when_bidirectional() {
+        let mut tape: Tape<u8> = Tape::new(4, TapeBoundary::Grow);
+        tape.set_current(9);
+
+        assert_eq!(tape.move_left(), Err(TapeError::HeadOutOfBounds));
+
+        tape.set_growth(TapeGrowth {
+            policy: GrowthPolicy::Chunk(3),
+            max_size: None,
+            bidirectional: true,
+        });
+        tape.move_left().unwrap();
+
+        assert_eq!(tape.len(), 7);
+        assert_eq!(tape.head(), 2);
+        assert_eq!(tape.origin(), 3);
+        assert_eq!(tape.cell(tape.origin()), 9);
+    }
+
+    #[test]
+    fn tape_stops_growing_at_max_size() {
+        let mut tape: Tape<u8> = Tape::new(2, TapeBoundary::Grow);
+        tape.set_growth(TapeGrowth {
+            policy: GrowthPolicy::Double,
+            max_size: Some(3),
+            bidirectional: false,
+        });
+
+        tape.move_by(2).unwrap();
+        assert_eq!(tape.len(), 3);
+        assert_eq!(tape.move_right(), Err(TapeError::TooBig));
+
+        // The smaller of the limit and max_size stops the tape growing
+        let mut tape: Tape<u8> = Tape::new(4, TapeBoundary::Grow);
+        assert_eq!(tape.set_limit(Some(3)), Err(TapeError::LimitExceeded));
+        tape.set_limit(Some(6)).unwrap();
+        tape.move_by(5).unwrap();
+        assert_eq!(tape.len(), 6);
+        assert_eq!(tape.move_right(), Err(TapeError::LimitExceeded));
+        tape.set_growth(TapeGrowth {
+            policy: GrowthPolicy::Chunk(1),
+            max_size: Some(6),
+            bidirectional: false,
+        });
+        tape.set_limit(Some(10)).unwrap();
+        assert_eq!(tape.move_right(), Err(TapeError::TooBig));
+        assert_eq!(tape.len(), 6);
+    }
+
+    #[test]
+    fn wrapping_tape_is_circular() {
+        let mut tape: Tape<u8> = Tape::new(3, TapeBoundary::Wrap);
+
+        tape.move_left().unwrap();
+        assert_eq!(tape.head(), 2);
+        tape.move_right().unwrap();
+        assert_eq!(tape.head(), 0);
+        assert_eq!(tape.index_at(-7), Ok(2));
+        assert_eq!(tape.len(), 3);
+    }
+
+    #[test]
+    fn clamped_tape_stays_at_the_ends() {
+        let mut tape: Tape<u8> = Tape::new(3, TapeBoundary::Clamp);
+
+        tape.move_left().unwrap();
+        assert_eq!(tape.head(), 0);
+        tape.move_by(10).unwrap();
+        assert_eq!(tape.head(), 2);
+        assert_eq!(tape.len(), 3);
+    }
+
+    #[test]
+    fn window_marks_the_head() {
+        let mut tape: Tape<u8> = Tape::new(5, TapeBoundary::Error);
+        tape.move_by(3).unwrap();
+        tape.set_current(7);
+        let mut out = Vec::new();
+
+        tape.write_window(&mut out, 2).unwrap();
+        assert_eq!(out, b"#1=0 #2=0 [#3=7] #4=0 \n");
+
+        let mut out = Vec::new();
+        tape.write_window(&mut out, usize::MAX).unwrap();
+        assert_eq!(out, b"#0=0 #1=0 #2=0 [#3=7] #4=0 \n");
+    }
+}
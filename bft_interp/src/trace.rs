@@ -0,0 +1,91 @@
+//! A trace of every instruction the virtual machine runs
+//!
+//! Traces are written as JSON Lines, one object per instruction, so that
+//! the traces from two versions of the interpreter can be compared line by
+//! line.  The keys are always written in the same order:
+//!
+//! ```text
+//! {"step":1,"pc":0,"line":0,"col":0,"command":"+","head":0,"before":0,"after":1}
+//! ```
+//!
+//! `head` is the cell under the head when the instruction started, `before`
+//! and `after` are the values of that cell before and after it ran.  Lines
+//! and columns are zero-based, as they are everywhere else.
+
+use crate::CellKind;
+use bft_types::{BFCommand, InputInstruction};
+use std::fmt;
+use std::io::{self, Write};
+
+/// What happened when a single instruction ran
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceRecord<T> {
+    /// The number of instructions run, counting this one
+    pub step: u64,
+    /// The index of the instruction
+    pub program_counter: usize,
+    pub instruction: InputInstruction,
+    /// The index of the cell under the head when the instruction started
+    pub head: usize,
+    /// The value of the cell before the instruction ran
+    pub before: T,
+    /// The value of the cell after the instruction ran
+    pub after: T,
+}
+
+impl<T> TraceRecord<T>
+where
+    T: CellKind,
+{
+    /// Write the record as a line of JSON
+    pub fn write_json(&self, out: &mut impl Write) -> io::Result<()> {
+        // The command is one of the BF characters, none of which need
+        // escaping in a JSON string
+        writeln!(
+            out,
+            "{{\"step\":{},\"pc\":{},\"line\":{},\"col\":{},\"command\":\"{}\",\"head\":{},\"before\":{},\"after\":{}}}",
+            self.step,
+            self.program_counter,
+            self.instruction.line_number(),
+            self.instruction.column_number(),
+            BFCommand::to_char(self.instruction.get_command()),
+            self.head,
+            self.before,
+            self.after
+        )
+    }
+}
+
+/// Where the virtual machine writes its trace
+pub(crate) struct TraceWriter<'a>(pub(crate) Box<dyn Write + 'a>);
+
+impl fmt::Debug for TraceWriter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TraceWriter")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TraceRecord;
+    use bft_types::{BFCommand, InputInstruction};
+
+    #[test]
+    fn records_are_json_lines() {
+        let record = TraceRecord {
+            step: 3,
+            program_counter: 2,
+            instruction: InputInstruction::new(BFCommand::DecrementByte, 1, 4),
+            head: 5,
+            before: 0i8,
+            after: -1,
+        };
+        let mut out = Vec::new();
+
+        record.write_json(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"step\":3,\"pc\":2,\"line\":1,\"col\":4,\"command\":\"-\",\"head\":5,\"before\":0,\"after\":-1}\n"
+        );
+    }
+}
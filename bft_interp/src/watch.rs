@@ -0,0 +1,67 @@
+//! Watchpoints which pause the virtual machine when a tape cell changes
+//!
+//! `BFVirtualMachine` takes and reports cells by their index on the tape,
+//! the same index `tape_pointer` returns, but watchpoints keep the position
+//! of the cell counted from the tape's origin.  A tape which grows to the
+//! left moves every cell along, the watched cell keeps its position.
+
+/// What a watchpoint is waiting for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchCondition<T> {
+    /// Pause whenever the cell is given a different value
+    Changed,
+    /// Pause when the cell changes to this value
+    Equals(T),
+}
+
+/// A watch on a single tape cell
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watchpoint<T> {
+    /// The position of the watched cell, counted from the origin of the tape
+    pub position: isize,
+    pub condition: WatchCondition<T>,
+}
+
+impl<T> Watchpoint<T>
+where
+    T: Copy + PartialEq,
+{
+    pub fn new(position: isize, condition: WatchCondition<T>) -> Watchpoint<T> {
+        Watchpoint {
+            position,
+            condition,
+        }
+    }
+
+    /// Return whether the cell at position going from before to after should
+    /// pause the machine
+    pub fn is_triggered(&self, position: isize, before: T, after: T) -> bool {
+        if position != self.position || before == after {
+            return false;
+        }
+
+        match self.condition {
+            WatchCondition::Changed => true,
+            WatchCondition::Equals(value) => after == value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WatchCondition, Watchpoint};
+
+    #[test]
+    fn watchpoints_trigger_on_their_own_cell() {
+        let changed = Watchpoint::new(3, WatchCondition::Changed);
+        assert!(changed.is_triggered(3, 0u8, 1));
+        assert!(!changed.is_triggered(3, 1u8, 1));
+        assert!(!changed.is_triggered(2, 0u8, 1));
+        assert!(!changed.is_triggered(-3, 0u8, 1));
+
+        let equals = Watchpoint::new(3, WatchCondition::Equals(5u8));
+        assert!(equals.is_triggered(3, 4, 5));
+        assert!(!equals.is_triggered(3, 5, 6));
+        assert!(!equals.is_triggered(3, 5, 5));
+    }
+}
@@ -0,0 +1,283 @@
+//! An intermediate representation for BrainFuck programs
+//!
+//! The IR is a flat list of operations, each of which remembers the span of
+//! `InputInstruction`s it was built from.  A freshly lowered program has one
+//! operation per command, optimisation passes then fold and replace runs of
+//! operations with the richer ones such as `MulAdd` or `ScanRight`.
+
+use crate::{BFCommand, BFProgram, InputInstruction};
+use std::fmt;
+
+/// The range of commands in a `BFProgram` that an operation came from,
+/// `start` is inclusive and `end` is exclusive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// Create a new Span covering the commands from start up to, but not
+    /// including, end
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+
+    /// Return the smallest Span covering both spans
+    pub fn merge(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+
+    /// Return the number of commands covered, a Span which ends before it
+    /// starts covers none
+    pub fn len(&self) -> usize {
+        self.end.saturating_sub(self.start)
+    }
+
+    /// Return whether the Span covers no commands
+    pub fn is_empty(&self) -> bool {
+        self.end <= self.start
+    }
+
+    /// Return the commands covered by the Span
+    pub fn instructions<'p>(&self, program: &'p BFProgram) -> &'p [InputInstruction] {
+        &program.commands()[self.start..self.end.max(self.start)]
+    }
+}
+
+/// An IR operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// Add to the cell at the head, negative values subtract
+    Add(i32),
+    /// Move the head, negative values move left
+    Move(isize),
+    /// Set the cell at the head to zero
+    SetZero,
+    /// Add the cell at the head multiplied by factor to the cell at
+    /// head + offset, the cell at the head is left alone
+    MulAdd { offset: isize, factor: i32 },
+    /// Move the head left until it is on a zero cell
+    ScanLeft,
+    /// Move the head right until it is on a zero cell
+    ScanRight,
+    /// Output the cell at the head
+    Output,
+    /// Read a byte of input into the cell at the head
+    Input,
+    /// Dump the state of the machine, from the `#` extension
+    DebugDump,
+    /// If the cell at the head is zero, continue after the op at index end
+    LoopStart { end: usize },
+    /// If the cell at the head is nonzero, continue after the op at index start
+    LoopEnd { start: usize },
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Add(n) => write!(f, "add {}", n),
+            Op::Move(n) => write!(f, "move {}", n),
+            Op::SetZero => write!(f, "set-zero"),
+            Op::MulAdd { offset, factor } => write!(f, "mul-add {} {}", offset, factor),
+            Op::ScanLeft => write!(f, "scan-left"),
+            Op::ScanRight => write!(f, "scan-right"),
+            Op::Output => write!(f, "output"),
+            Op::Input => write!(f, "input"),
+            Op::DebugDump => write!(f, "debug-dump"),
+            Op::LoopStart { end } => write!(f, "loop-start {}", end),
+            Op::LoopEnd { start } => write!(f, "loop-end {}", start),
+        }
+    }
+}
+
+/// An IR operation along with the span of commands it was built from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IrInstruction {
+    op: Op,
+    span: Span,
+}
+
+impl IrInstruction {
+    /// Create a new IrInstruction
+    pub fn new(op: Op, span: Span) -> IrInstruction {
+        IrInstruction { op, span }
+    }
+
+    /// Return the operation
+    pub fn op(&self) -> Op {
+        self.op
+    }
+
+    /// Return the span of commands the operation came from
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// Return the first command the operation came from, which is the one
+    /// to report when something goes wrong
+    pub fn source<'p>(&self, program: &'p BFProgram) -> &'p InputInstruction {
+        program.get_command(self.span.start)
+    }
+}
+
+/// A lowered BrainFuck program
+#[derive(Debug, Clone, PartialEq)]
+pub struct IrProgram {
+    ops: Vec<IrInstruction>,
+}
+
+impl IrProgram {
+    /// Lower a BFProgram to one operation per command
+    pub fn lower(program: &BFProgram) -> IrProgram {
+        let ops = program
+            .commands()
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| {
+                let op = match instruction.get_command() {
+                    BFCommand::IncrementPointer => Op::Move(1),
+                    BFCommand::DecrementPointer => Op::Move(-1),
+                    BFCommand::IncrementByte => Op::Add(1),
+                    BFCommand::DecrementByte => Op::Add(-1),
+                    BFCommand::OutputByte => Op::Output,
+                    BFCommand::InputByte => Op::Input,
+                    BFCommand::DebugDump => Op::DebugDump,
+                    BFCommand::IfZeroJumpForward => Op::LoopStart {
+                        end: program.matching_bracket(index).unwrap_or(index),
+                    },
+                    BFCommand::IfNonZeroJumpBack => Op::LoopEnd {
+                        start: program.matching_bracket(index).unwrap_or(index),
+                    },
+                };
+                IrInstruction::new(op, Span::new(index, index + 1))
+            })
+            .collect();
+
+        IrProgram { ops }
+    }
+
+    /// Create an IrProgram from a list of operations, the loop targets are
+    /// recalculated so they need not be correct
+    ///
+    /// # Panics
+    ///
+    /// Panics if the loop operations are not balanced
+    pub fn from_ops(ops: Vec<IrInstruction>) -> IrProgram {
+        let mut program = IrProgram { ops };
+        program.link_loops();
+        program
+    }
+
+    /// Return all of the operations
+    pub fn ops(&self) -> &[IrInstruction] {
+        &self.ops
+    }
+
+    /// Return the number of operations
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Return whether there are no operations
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Take the operations out of the program
+    pub fn into_ops(self) -> Vec<IrInstruction> {
+        self.ops
+    }
+
+    /// Recalculate the targets of the loop operations after the list of
+    /// operations has changed
+    fn link_loops(&mut self) {
+        let mut open_loops: Vec<usize> = Vec::new();
+
+        for index in 0..self.ops.len() {
+            match self.ops[index].op {
+                Op::LoopStart { .. } => open_loops.push(index),
+                Op::LoopEnd { .. } => {
+                    let start = open_loops.pop().expect("Unbalanced IR loops");
+                    self.ops[start].op = Op::LoopStart { end: index };
+                    self.ops[index].op = Op::LoopEnd { start };
+                }
+                _ => (),
+            }
+        }
+
+        assert!(open_loops.is_empty(), "Unbalanced IR loops");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IrInstruction, IrProgram, Op, Span};
+    use crate::BFProgram;
+
+    #[test]
+    fn lowering_is_one_op_per_command() {
+        let program = BFProgram::from_str("lower.bf", "+>[-<.,]").unwrap();
+        let ir = IrProgram::lower(&program);
+
+        let ops: Vec<Op> = ir.ops().iter().map(|i| i.op()).collect();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Add(1),
+                Op::Move(1),
+                Op::LoopStart { end: 7 },
+                Op::Add(-1),
+                Op::Move(-1),
+                Op::Output,
+                Op::Input,
+                Op::LoopEnd { start: 2 },
+            ]
+        );
+    }
+
+    #[test]
+    fn lowered_ops_keep_their_source() {
+        let program = BFProgram::from_str("source.bf", "+\n  [-]").unwrap();
+        let ir = IrProgram::lower(&program);
+
+        assert_eq!(ir.ops()[2].span(), Span::new(2, 3));
+
+        let source = ir.ops()[2].source(&program);
+        assert_eq!(source.line_number(), 1);
+        assert_eq!(source.column_number(), 3);
+    }
+
+    #[test]
+    fn from_ops_relinks_loops() {
+        let ops = vec![
+            IrInstruction::new(Op::Add(2), Span::new(0, 2)),
+            IrInstruction::new(Op::LoopStart { end: 0 }, Span::new(2, 3)),
+            IrInstruction::new(Op::LoopStart { end: 0 }, Span::new(3, 4)),
+            IrInstruction::new(Op::LoopEnd { start: 0 }, Span::new(4, 5)),
+            IrInstruction::new(Op::LoopEnd { start: 0 }, Span::new(5, 6)),
+        ];
+        let ir = IrProgram::from_ops(ops);
+
+        assert_eq!(ir.ops()[1].op(), Op::LoopStart { end: 4 });
+        assert_eq!(ir.ops()[2].op(), Op::LoopStart { end: 3 });
+        assert_eq!(ir.ops()[3].op(), Op::LoopEnd { start: 2 });
+        assert_eq!(ir.ops()[4].op(), Op::LoopEnd { start: 1 });
+    }
+
+    #[test]
+    fn spans_merge() {
+        let span = Span::new(3, 5).merge(Span::new(1, 2));
+
+        assert_eq!(span, Span::new(1, 5));
+        assert_eq!(span.len(), 4);
+        assert!(!span.is_empty());
+
+        let backwards = Span::new(5, 3);
+        assert_eq!(backwards.len(), 0);
+        assert!(backwards.is_empty());
+    }
+}
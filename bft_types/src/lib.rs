@@ -5,26 +5,228 @@
 //! _Woop Again_
 //! ==========
 
+pub mod ir;
+pub mod passes;
+
+use std::error::Error;
 use std::fmt;
-use std::io::Result;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 
+/// Errors which can occur while loading a BrainFuck program
+#[derive(Debug)]
+pub enum ParseError {
+    /// The program source could not be read
+    Io {
+        filename: PathBuf,
+        source: std::io::Error,
+    },
+    /// The program source is not valid UTF-8, the line and column
+    /// are those of the first invalid byte
+    InvalidEncoding {
+        filename: PathBuf,
+        line_number: usize,
+        column_number: usize,
+    },
+    /// One or more brackets in the program have no matching partner,
+    /// every unmatched bracket is listed in program order
+    UnbalancedBrackets {
+        filename: PathBuf,
+        errors: Vec<BracketError>,
+    },
+}
+
+impl ParseError {
+    /// Return the filename of the program which failed to load
+    pub fn filename(&self) -> &PathBuf {
+        match self {
+            ParseError::Io { filename, .. } => filename,
+            ParseError::InvalidEncoding { filename, .. } => filename,
+            ParseError::UnbalancedBrackets { filename, .. } => filename,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io { filename, source } => {
+                write!(f, "{}: unable to read file: {}", filename.display(), source)
+            }
+            ParseError::InvalidEncoding {
+                filename,
+                line_number,
+                column_number,
+            } => write!(
+                f,
+                "{}: invalid UTF-8 at [Line {}, Col {}]",
+                filename.display(),
+                line_number,
+                column_number
+            ),
+            ParseError::UnbalancedBrackets { filename, errors } => {
+                write!(f, "{}: unbalanced brackets", filename.display())?;
+                for error in errors {
+                    write!(f, "\n    {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// A bracket which has no matching partner
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BracketError {
+    /// A `[` which is never closed
+    UnmatchedOpen(InputInstruction),
+    /// A `]` which was never opened
+    UnmatchedClose(InputInstruction),
+}
+
+impl BracketError {
+    /// Return the bracket instruction which is unmatched
+    pub fn instruction(&self) -> &InputInstruction {
+        match self {
+            BracketError::UnmatchedOpen(instruction) => instruction,
+            BracketError::UnmatchedClose(instruction) => instruction,
+        }
+    }
+}
+
+impl fmt::Display for BracketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BracketError::UnmatchedOpen(instruction) => {
+                write!(f, "unclosed bracket {}", instruction)
+            }
+            BracketError::UnmatchedClose(instruction) => {
+                write!(f, "unopened bracket {}", instruction)
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 /// This is a struct containing:
 /// * The BrainFuck commands in a Vector
 /// * The filename of which they were read from
+/// * The index of the matching bracket for every bracket command
 pub struct BFProgram {
     filename: PathBuf,
     commands: Vec<InputInstruction>,
+    jump_table: Vec<Option<usize>>,
 }
 
 impl BFProgram {
     /// Create a new BFProgram
     ///
+    /// # Panics
+    ///
+    /// Panics if the program can not be loaded, use `try_new` to handle
+    /// the error instead
     pub fn new<T: AsRef<Path>>(a_path: T) -> BFProgram {
-        BFProgram {
+        BFProgram::try_new(a_path).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Create a new BFProgram, returning an error if the file can not be
+    /// read, is not UTF-8 or has unbalanced brackets
+    pub fn try_new<T: AsRef<Path>>(a_path: T) -> Result<BFProgram, ParseError> {
+        BFProgram::try_new_with(a_path, Extensions::default())
+    }
+
+    /// Like try_new, but also recognise the enabled extension commands
+    pub fn try_new_with<T: AsRef<Path>>(
+        a_path: T,
+        extensions: Extensions,
+    ) -> Result<BFProgram, ParseError> {
+        let content = std::fs::read(&a_path).map_err(|source| ParseError::Io {
             filename: a_path.as_ref().to_path_buf(),
-            commands: BFProgram::from_file(a_path).expect("Unable to Read file"), // get content here.emulrate .collect etc
+            source,
+        })?;
+
+        BFProgram::from_bytes_with(a_path, &content, extensions)
+    }
+
+    /// Create a new BFProgram from source text, the filename is only used
+    /// for error reporting
+    pub fn from_str<T: AsRef<Path>>(filename: T, content: &str) -> Result<BFProgram, ParseError> {
+        BFProgram::from_str_with(filename, content, Extensions::default())
+    }
+
+    /// Like from_str, but also recognise the enabled extension commands
+    pub fn from_str_with<T: AsRef<Path>>(
+        filename: T,
+        content: &str,
+        extensions: Extensions,
+    ) -> Result<BFProgram, ParseError> {
+        let filename = filename.as_ref().to_path_buf();
+        let commands = BFProgram::parse(content, &extensions);
+
+        match BFProgram::match_brackets(&commands) {
+            Ok(jump_table) => Ok(BFProgram {
+                filename,
+                commands,
+                jump_table,
+            }),
+            Err(errors) => Err(ParseError::UnbalancedBrackets { filename, errors }),
+        }
+    }
+
+    /// Create a new BFProgram from anything which implements Read
+    pub fn from_reader<T: AsRef<Path>, R: Read>(
+        filename: T,
+        mut reader: R,
+    ) -> Result<BFProgram, ParseError> {
+        let mut content = Vec::new();
+        reader
+            .read_to_end(&mut content)
+            .map_err(|source| ParseError::Io {
+                filename: filename.as_ref().to_path_buf(),
+                source,
+            })?;
+
+        BFProgram::from_bytes(filename, &content)
+    }
+
+    /// Create a new BFProgram from raw bytes, which must be UTF-8
+    pub fn from_bytes<T: AsRef<Path>>(
+        filename: T,
+        content: &[u8],
+    ) -> Result<BFProgram, ParseError> {
+        BFProgram::from_bytes_with(filename, content, Extensions::default())
+    }
+
+    /// Like from_bytes, but also recognise the enabled extension commands
+    pub fn from_bytes_with<T: AsRef<Path>>(
+        filename: T,
+        content: &[u8],
+        extensions: Extensions,
+    ) -> Result<BFProgram, ParseError> {
+        match std::str::from_utf8(content) {
+            Ok(text) => BFProgram::from_str_with(filename, text, extensions),
+            Err(e) => {
+                // Everything before the bad byte is valid, so we can work out
+                // where it is in the same way as the commands are numbered
+                let valid = std::str::from_utf8(&content[..e.valid_up_to()]).unwrap_or_default();
+                let line_number = valid.matches('\n').count();
+                let column_number = valid.rsplit('\n').next().unwrap_or("").chars().count();
+
+                Err(ParseError::InvalidEncoding {
+                    filename: filename.as_ref().to_path_buf(),
+                    line_number,
+                    column_number,
+                })
+            }
         }
     }
 
@@ -43,31 +245,89 @@ impl BFProgram {
         &self.commands
     }
 
-    // Add a new cell
+    /// Add a new cell.  The brackets are paired up again afterwards, while
+    /// they do not balance none of them has a match.
+    #[deprecated(note = "a program should be loaded whole, so that its brackets are checked")]
     pub fn add_command(&mut self, instruction: InputInstruction) {
         self.commands.push(instruction);
+        self.jump_table = BFProgram::match_brackets(&self.commands)
+            .unwrap_or_else(|_| vec![None; self.commands.len()]);
     }
 
-    /// Create a new BFProgram from a file
+    /// Return the index of the bracket matching the one at the given index,
+    /// or None if the command at the index is not a bracket
+    pub fn matching_bracket(&self, index: usize) -> Option<usize> {
+        self.jump_table.get(index).copied().flatten()
+    }
+
+    /// Read the BrainFuck commands from a file, without checking the brackets.
+    /// `try_new` reads and checks a whole program, returning a ParseError.
     ///
-    pub fn from_file<T: AsRef<Path>>(a_path: T) -> Result<Vec<InputInstruction>> {
+    pub fn from_file<T: AsRef<Path>>(a_path: T) -> std::io::Result<Vec<InputInstruction>> {
         let content = std::fs::read_to_string(&a_path)?;
+
+        Ok(BFProgram::parse(&content, &Extensions::default()))
+    }
+
+    fn parse(content: &str, extensions: &Extensions) -> Vec<InputInstruction> {
         let mut commands = Vec::new();
 
         for (line_num, line) in content.lines().enumerate() {
             for (col_num, achar) in line.chars().enumerate() {
-                if let Some(v) = BFCommand::from_char(achar) {
+                if let Some(v) = BFCommand::from_char_with(achar, extensions) {
                     let instruction = InputInstruction::new(v, line_num, col_num);
                     commands.push(instruction);
                 }
             }
         }
 
-        Ok(commands)
+        commands
+    }
+
+    /// Check that every bracket in the commands has a partner, returning
+    /// all of the unmatched brackets in program order if not
+    pub fn validate_brackets(commands: &[InputInstruction]) -> Result<(), Vec<BracketError>> {
+        BFProgram::match_brackets(commands).map(|_| ())
+    }
+
+    /// Pair up the brackets in the commands, returning a table which holds
+    /// the index of the matching bracket for every bracket command, or all
+    /// of the unmatched brackets in program order
+    pub fn match_brackets(
+        commands: &[InputInstruction],
+    ) -> Result<Vec<Option<usize>>, Vec<BracketError>> {
+        let mut jump_table = vec![None; commands.len()];
+        let mut open_brackets: Vec<usize> = Vec::new();
+        let mut errors: Vec<(usize, BracketError)> = Vec::new();
+
+        for (index, instruction) in commands.iter().enumerate() {
+            match instruction.get_command() {
+                BFCommand::IfZeroJumpForward => open_brackets.push(index),
+                BFCommand::IfNonZeroJumpBack => match open_brackets.pop() {
+                    Some(open) => {
+                        jump_table[open] = Some(index);
+                        jump_table[index] = Some(open);
+                    }
+                    None => errors.push((index, BracketError::UnmatchedClose(*instruction))),
+                },
+                _ => (),
+            }
+        }
+
+        for index in open_brackets {
+            errors.push((index, BracketError::UnmatchedOpen(commands[index])));
+        }
+
+        if errors.is_empty() {
+            Ok(jump_table)
+        } else {
+            errors.sort_by_key(|(index, _)| *index);
+            Err(errors.into_iter().map(|(_, error)| error).collect())
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BFCommand {
     IncrementPointer,  //>
     DecrementPointer,  //<
@@ -77,11 +337,25 @@ pub enum BFCommand {
     InputByte,         //,
     IfZeroJumpForward, //[
     IfNonZeroJumpBack, //]
+    DebugDump,         //# only with Extensions::debug_dump
+}
+
+/// Optional commands beyond the standard eight, all off by default
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Extensions {
+    /// Treat `#` as a command which dumps the state of the machine, rather
+    /// than as a comment
+    pub debug_dump: bool,
 }
 
 // Option because it could be none
 impl BFCommand {
     pub fn from_char(raw_command: char) -> Option<BFCommand> {
+        BFCommand::from_char_with(raw_command, &Extensions::default())
+    }
+
+    /// Like from_char, but also recognise the enabled extension commands
+    pub fn from_char_with(raw_command: char, extensions: &Extensions) -> Option<BFCommand> {
         match raw_command {
             '>' => Some(BFCommand::IncrementPointer),
             '<' => Some(BFCommand::DecrementPointer),
@@ -91,6 +365,7 @@ impl BFCommand {
             ',' => Some(BFCommand::InputByte),
             '[' => Some(BFCommand::IfZeroJumpForward),
             ']' => Some(BFCommand::IfNonZeroJumpBack),
+            '#' if extensions.debug_dump => Some(BFCommand::DebugDump),
             _ => None,
         }
     }
@@ -105,6 +380,7 @@ impl BFCommand {
             BFCommand::InputByte => ',',
             BFCommand::IfZeroJumpForward => '[',
             BFCommand::IfNonZeroJumpBack => ']',
+            BFCommand::DebugDump => '#',
         }
     }
 }
@@ -112,7 +388,7 @@ impl BFCommand {
 /// Struct to represent an Brainfuck command,
 /// line number
 /// column number
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct InputInstruction {
     command: BFCommand,
     line_number: usize,
@@ -161,7 +437,12 @@ impl fmt::Display for InputInstruction {
 mod tests {
     use super::BFCommand;
     use super::BFProgram;
+    use super::BracketError;
+    use super::Extensions;
+    use super::InputInstruction;
+    use super::ParseError;
     use std::env;
+    use std::io::Cursor;
     #[test]
     fn value_is_correct() {
         let mut path = env::current_dir().unwrap();
@@ -176,21 +457,21 @@ mod tests {
             let a_bfcommand: BFCommand = cell.get_command();
 
             if program_is_valid {
-                program_is_valid = match a_bfcommand {
-                    BFCommand::IncrementPointer => true,
-                    BFCommand::DecrementPointer => true,
-                    BFCommand::IncrementByte => true,
-                    BFCommand::DecrementByte => true,
-                    BFCommand::OutputByte => true,
-                    BFCommand::InputByte => true,
-                    BFCommand::IfZeroJumpForward => true,
-                    BFCommand::IfNonZeroJumpBack => true,
-                    _ => false,
-                }
+                program_is_valid = matches!(
+                    a_bfcommand,
+                    BFCommand::IncrementPointer
+                        | BFCommand::DecrementPointer
+                        | BFCommand::IncrementByte
+                        | BFCommand::DecrementByte
+                        | BFCommand::OutputByte
+                        | BFCommand::InputByte
+                        | BFCommand::IfZeroJumpForward
+                        | BFCommand::IfNonZeroJumpBack
+                );
             }
         }
 
-        assert_eq!(program_is_valid, true);
+        assert!(program_is_valid);
     }
 
     #[test]
@@ -208,4 +489,123 @@ mod tests {
         assert_eq!(instruct2.line_number(), 9);
         assert_eq!(instruct2.column_number(), 11);
     }
+
+    #[test]
+    fn missing_file_is_an_error() {
+        let mut path = env::current_dir().unwrap();
+        path.set_file_name("no_such_program.bf");
+
+        match BFProgram::try_new(&path) {
+            Err(ParseError::Io { filename, .. }) => assert_eq!(filename, path),
+            other => panic!("Expected an IO error, got {:?}", other),
+        }
+
+        let error = BFProgram::from_file(&path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn invalid_utf8_reports_location() {
+        let source = b"++\n+-\xff[]";
+
+        match BFProgram::from_bytes("bad.bf", source) {
+            Err(ParseError::InvalidEncoding {
+                line_number,
+                column_number,
+                ..
+            }) => {
+                assert_eq!(line_number, 1);
+                assert_eq!(column_number, 2);
+            }
+            other => panic!("Expected an encoding error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unbalanced_brackets_are_an_error() {
+        match BFProgram::from_str("open.bf", "+[\n[-]") {
+            Err(ParseError::UnbalancedBrackets { errors, .. }) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].instruction().line_number(), 0);
+                assert_eq!(errors[0].instruction().column_number(), 1);
+            }
+            other => panic!("Expected a bracket error, got {:?}", other),
+        }
+
+        assert!(BFProgram::from_str("close.bf", "+]").is_err());
+    }
+
+    #[test]
+    fn every_unmatched_bracket_is_reported() {
+        match BFProgram::from_str("many.bf", "]+[\n[-]]\n][") {
+            Err(ParseError::UnbalancedBrackets { errors, .. }) => {
+                let found: Vec<(bool, usize, usize)> = errors
+                    .iter()
+                    .map(|e| {
+                        let open = matches!(e, BracketError::UnmatchedOpen(_));
+                        (
+                            open,
+                            e.instruction().line_number(),
+                            e.instruction().column_number(),
+                        )
+                    })
+                    .collect();
+
+                assert_eq!(found, vec![(false, 0, 0), (false, 2, 0), (true, 2, 1)]);
+            }
+            other => panic!("Expected bracket errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn from_reader_reads_program() {
+        let program = BFProgram::from_reader("reader.bf", Cursor::new("+[->+<]")).unwrap();
+
+        assert_eq!(program.commands().len(), 7);
+        assert_eq!(program.filename().to_str(), Some("reader.bf"));
+    }
+
+    #[test]
+    fn matching_brackets_are_paired() {
+        let program = BFProgram::from_str("nested.bf", "+[>[-]<-]>[]").unwrap();
+
+        assert_eq!(program.matching_bracket(0), None);
+        assert_eq!(program.matching_bracket(1), Some(8));
+        assert_eq!(program.matching_bracket(8), Some(1));
+        assert_eq!(program.matching_bracket(3), Some(5));
+        assert_eq!(program.matching_bracket(5), Some(3));
+        assert_eq!(program.matching_bracket(10), Some(11));
+        assert_eq!(program.matching_bracket(11), Some(10));
+        assert_eq!(program.matching_bracket(100), None);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn added_commands_are_paired() {
+        let mut program = BFProgram::from_str("add.bf", "+").unwrap();
+
+        program.add_command(InputInstruction::new(BFCommand::IfZeroJumpForward, 0, 1));
+        assert_eq!(program.matching_bracket(1), None);
+        program.add_command(InputInstruction::new(BFCommand::IfNonZeroJumpBack, 0, 2));
+        assert_eq!(program.commands().len(), 3);
+        assert_eq!(program.matching_bracket(1), Some(2));
+        assert_eq!(program.matching_bracket(2), Some(1));
+    }
+
+    #[test]
+    fn debug_dump_is_opt_in() {
+        let source = "+# comment\n#.";
+        let plain = BFProgram::from_str("dump.bf", source).unwrap();
+        assert_eq!(plain.commands().len(), 2);
+
+        let extensions = Extensions { debug_dump: true };
+        let program = BFProgram::from_bytes_with("dump.bf", source.as_bytes(), extensions).unwrap();
+        let commands: Vec<char> = program
+            .commands()
+            .iter()
+            .map(|c| BFCommand::to_char(c.get_command()))
+            .collect();
+        assert_eq!(commands, ['+', '#', '#', '.']);
+        assert_eq!(program.commands()[2].line_number(), 1);
+    }
 }
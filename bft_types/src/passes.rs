@@ -0,0 +1,545 @@
+//! Optimisation passes over the IR
+//!
+//! Each pass rewrites the list of IR operations and can be switched on and
+//! off by name through a `PassManager`, so a misbehaving pass can be found
+//! by comparing the optimised program against the plain interpreter.
+//!
+//! The passes assume cells wrap on overflow.  Folding `<>` skips the cell
+//! to the left of the head, which matters if the head would have fallen off
+//! the tape there, so moves are only folded across a change of direction
+//! when the PassManager is told the head can not fall off.
+
+use crate::ir::{IrInstruction, IrProgram, Op};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+/// An optimisation pass
+pub trait Pass {
+    /// The name used to select the pass
+    fn name(&self) -> &'static str;
+
+    /// Rewrite the operations, the loop targets in the operations are
+    /// correct on entry but need not be on exit
+    fn run(&self, ops: Vec<IrInstruction>) -> Vec<IrInstruction>;
+}
+
+/// Fold runs of `+`/`-` into a single `Add` and runs of `<`/`>` into a
+/// single `Move`.  Unless skip_cells is set, only moves in the same
+/// direction are folded, see `PassManager::set_skip_cells`.
+#[derive(Debug, Default)]
+pub struct FoldRuns {
+    pub skip_cells: bool,
+}
+
+impl Pass for FoldRuns {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&self, ops: Vec<IrInstruction>) -> Vec<IrInstruction> {
+        let mut folded: Vec<IrInstruction> = Vec::with_capacity(ops.len());
+
+        for instruction in ops {
+            let merged = match (folded.last().map(|i| i.op()), instruction.op()) {
+                (Some(Op::Add(a)), Op::Add(b)) => Some(Op::Add(a.wrapping_add(b))),
+                (Some(Op::Move(a)), Op::Move(b)) if self.skip_cells || a.signum() == b.signum() => {
+                    Some(Op::Move(a + b))
+                }
+                _ => None,
+            };
+
+            match merged {
+                Some(op) => {
+                    let last = folded.pop().unwrap();
+                    folded.push(IrInstruction::new(
+                        op,
+                        last.span().merge(instruction.span()),
+                    ));
+                }
+                None => folded.push(instruction),
+            }
+        }
+
+        folded
+    }
+}
+
+/// Remove neighbouring `Add`s or `Move`s which undo each other, such as
+/// `+-` or `<>`, along with any which do nothing at all.  Unless skip_cells
+/// is set moves are never removed, see `PassManager::set_skip_cells`.
+#[derive(Debug, Default)]
+pub struct CancelOpposites {
+    pub skip_cells: bool,
+}
+
+impl Pass for CancelOpposites {
+    fn name(&self) -> &'static str {
+        "cancel"
+    }
+
+    fn run(&self, ops: Vec<IrInstruction>) -> Vec<IrInstruction> {
+        let mut kept: Vec<IrInstruction> = Vec::with_capacity(ops.len());
+
+        for instruction in ops {
+            let cancels = match (kept.last().map(|i| i.op()), instruction.op()) {
+                (Some(Op::Add(a)), Op::Add(b)) => a.wrapping_add(b) == 0,
+                (Some(Op::Move(a)), Op::Move(b)) => self.skip_cells && a + b == 0,
+                _ => false,
+            };
+
+            if cancels {
+                kept.pop();
+            } else if !matches!(instruction.op(), Op::Add(0) | Op::Move(0)) {
+                kept.push(instruction);
+            }
+        }
+
+        kept
+    }
+}
+
+/// Replace `[-]` and `[+]` with `SetZero`
+#[derive(Debug, Default)]
+pub struct ClearLoops;
+
+impl Pass for ClearLoops {
+    fn name(&self) -> &'static str {
+        "clear-loops"
+    }
+
+    fn run(&self, ops: Vec<IrInstruction>) -> Vec<IrInstruction> {
+        replace_simple_loops(ops, |body| match body {
+            [Op::Add(1)] | [Op::Add(-1)] => Some(vec![Op::SetZero]),
+            _ => None,
+        })
+    }
+}
+
+/// Replace `[<]` and `[>]` with `ScanLeft` and `ScanRight`
+#[derive(Debug, Default)]
+pub struct ScanLoops;
+
+impl Pass for ScanLoops {
+    fn name(&self) -> &'static str {
+        "scan-loops"
+    }
+
+    fn run(&self, ops: Vec<IrInstruction>) -> Vec<IrInstruction> {
+        replace_simple_loops(ops, |body| match body {
+            [Op::Move(-1)] => Some(vec![Op::ScanLeft]),
+            [Op::Move(1)] => Some(vec![Op::ScanRight]),
+            _ => None,
+        })
+    }
+}
+
+/// Replace multiply and copy loops such as `[->++>+<<]`, which decrement
+/// the current cell once per iteration and return to it, with a `MulAdd`
+/// for every other cell they change followed by a `SetZero`
+#[derive(Debug, Default)]
+pub struct MulLoops;
+
+impl Pass for MulLoops {
+    fn name(&self) -> &'static str {
+        "mul-loops"
+    }
+
+    fn run(&self, ops: Vec<IrInstruction>) -> Vec<IrInstruction> {
+        replace_simple_loops(ops, |body| {
+            let mut offset: isize = 0;
+            let mut deltas: BTreeMap<isize, i32> = BTreeMap::new();
+
+            for op in body {
+                match op {
+                    Op::Add(n) => {
+                        let delta = deltas.entry(offset).or_insert(0);
+                        *delta = delta.wrapping_add(*n);
+                    }
+                    Op::Move(n) => offset += n,
+                    _ => return None,
+                }
+            }
+
+            if offset != 0 || deltas.remove(&0) != Some(-1) {
+                return None;
+            }
+
+            let mut replacement: Vec<Op> = deltas
+                .into_iter()
+                .filter(|(_, factor)| *factor != 0)
+                .map(|(offset, factor)| Op::MulAdd { offset, factor })
+                .collect();
+            replacement.push(Op::SetZero);
+
+            Some(replacement)
+        })
+    }
+}
+
+/// Remove loops which can never run: those at the very start of the
+/// program, such as an "initial comment loop", where every cell is still
+/// zero, and those straight after another loop, which only ends when the
+/// cell at the head is zero
+#[derive(Debug, Default)]
+pub struct DeadLoops;
+
+impl Pass for DeadLoops {
+    fn name(&self) -> &'static str {
+        "dead-loops"
+    }
+
+    fn run(&self, ops: Vec<IrInstruction>) -> Vec<IrInstruction> {
+        let mut kept: Vec<IrInstruction> = Vec::with_capacity(ops.len());
+        let mut index = 0;
+
+        while index < ops.len() {
+            if let Op::LoopStart { end } = ops[index].op() {
+                let head_is_zero =
+                    matches!(kept.last().map(|i| i.op()), None | Some(Op::LoopEnd { .. }));
+
+                if head_is_zero {
+                    index = end + 1;
+                    continue;
+                }
+            }
+
+            kept.push(ops[index]);
+            index += 1;
+        }
+
+        kept
+    }
+}
+
+/// Replace every loop whose body only contains `Add` and `Move` operations
+/// with the operations returned by the replace function, if it returns any
+fn replace_simple_loops<F>(ops: Vec<IrInstruction>, replace: F) -> Vec<IrInstruction>
+where
+    F: Fn(&[Op]) -> Option<Vec<Op>>,
+{
+    let mut replaced: Vec<IrInstruction> = Vec::with_capacity(ops.len());
+    let mut index = 0;
+
+    while index < ops.len() {
+        if let Op::LoopStart { end } = ops[index].op() {
+            let body: Vec<Op> = ops[index + 1..end].iter().map(|i| i.op()).collect();
+            let is_simple = body.iter().all(|op| matches!(op, Op::Add(_) | Op::Move(_)));
+
+            if is_simple {
+                if let Some(new_ops) = replace(&body) {
+                    let span = ops[index].span().merge(ops[end].span());
+                    replaced.extend(new_ops.into_iter().map(|op| IrInstruction::new(op, span)));
+                    index = end + 1;
+                    continue;
+                }
+            }
+        }
+
+        replaced.push(ops[index]);
+        index += 1;
+    }
+
+    replaced
+}
+
+/// The error returned when a pass is selected by a name which does not exist
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnknownPass(pub String);
+
+impl fmt::Display for UnknownPass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown optimisation pass '{}'", self.0)
+    }
+}
+
+impl Error for UnknownPass {}
+
+/// Runs a list of passes in order, each of which can be enabled or disabled
+pub struct PassManager {
+    passes: Vec<(Box<dyn Pass>, bool)>,
+}
+
+impl PassManager {
+    /// The names of the standard passes, in the order they are run
+    pub const PASS_NAMES: &'static [&'static str] = &[
+        "dead-loops",
+        "fold",
+        "cancel",
+        "clear-loops",
+        "scan-loops",
+        "mul-loops",
+    ];
+
+    /// The highest optimisation level
+    pub const MAX_OPT_LEVEL: u32 = 2;
+
+    /// Create a PassManager holding all of the standard passes, all disabled
+    pub fn new() -> PassManager {
+        PassManager {
+            passes: vec![
+                (Box::new(DeadLoops), false),
+                (Box::new(FoldRuns::default()), false),
+                (Box::new(CancelOpposites::default()), false),
+                (Box::new(ClearLoops), false),
+                (Box::new(ScanLoops), false),
+                (Box::new(MulLoops), false),
+            ],
+        }
+    }
+
+    /// Create a PassManager with the standard passes for an optimisation
+    /// level enabled:
+    /// * 0 - no passes
+    /// * 1 - fold and cancel
+    /// * 2 or more - every pass
+    pub fn with_opt_level(level: u32) -> PassManager {
+        let mut manager = PassManager::new();
+
+        for (pass, enabled) in manager.passes.iter_mut() {
+            *enabled = match pass.name() {
+                "fold" | "cancel" => level >= 1,
+                _ => level >= 2,
+            };
+        }
+
+        manager
+    }
+
+    /// Let the fold and cancel passes fold and remove moves which pass over
+    /// cells the head does not end up on, the default is not to.  This is
+    /// only the same as moving one cell at a time if the head can never fall
+    /// off the tape, because it wraps or grows without limit in both
+    /// directions.
+    pub fn set_skip_cells(&mut self, skip_cells: bool) {
+        for (pass, _) in self.passes.iter_mut() {
+            match pass.name() {
+                "fold" => *pass = Box::new(FoldRuns { skip_cells }),
+                "cancel" => *pass = Box::new(CancelOpposites { skip_cells }),
+                _ => (),
+            }
+        }
+    }
+
+    /// Add a pass to the end of the list, enabled
+    pub fn add_pass(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push((pass, true));
+    }
+
+    /// Enable or disable a pass by name
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> Result<(), UnknownPass> {
+        match self.passes.iter_mut().find(|(pass, _)| pass.name() == name) {
+            Some((_, pass_enabled)) => {
+                *pass_enabled = enabled;
+                Ok(())
+            }
+            None => Err(UnknownPass(name.to_string())),
+        }
+    }
+
+    /// Return the names of the enabled passes, in the order they are run
+    pub fn enabled_passes(&self) -> Vec<&'static str> {
+        self.passes
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(pass, _)| pass.name())
+            .collect()
+    }
+
+    /// Run all of the enabled passes over the program
+    pub fn run(&self, program: IrProgram) -> IrProgram {
+        self.passes
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .fold(program, |program, (pass, _)| {
+                IrProgram::from_ops(pass.run(program.into_ops()))
+            })
+    }
+}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        PassManager::new()
+    }
+}
+
+impl fmt::Debug for PassManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PassManager")
+            .field("enabled", &self.enabled_passes())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pass, PassManager, UnknownPass};
+    use crate::ir::{IrProgram, Op, Span};
+    use crate::BFProgram;
+
+    fn optimise(source: &str, passes: &[&str]) -> Vec<Op> {
+        optimise_with(source, passes, true)
+    }
+
+    fn optimise_with(source: &str, passes: &[&str], skip_cells: bool) -> Vec<Op> {
+        let program = BFProgram::from_str("test.bf", source).unwrap();
+        let mut manager = PassManager::new();
+        manager.set_skip_cells(skip_cells);
+        for pass in passes {
+            manager.set_enabled(pass, true).unwrap();
+        }
+
+        manager
+            .run(IrProgram::lower(&program))
+            .ops()
+            .iter()
+            .map(|i| i.op())
+            .collect()
+    }
+
+    #[test]
+    fn runs_are_folded() {
+        assert_eq!(
+            optimise("+++-->><<<.", &["fold"]),
+            vec![Op::Add(1), Op::Move(-1), Op::Output]
+        );
+        assert_eq!(
+            optimise_with("+++-->><<<.", &["fold"], false),
+            vec![Op::Add(1), Op::Move(2), Op::Move(-3), Op::Output]
+        );
+    }
+
+    #[test]
+    fn opposites_cancel() {
+        assert_eq!(
+            optimise("+-><++--+.", &["cancel"]),
+            vec![Op::Add(1), Op::Output]
+        );
+        assert_eq!(optimise("+-<>", &["fold", "cancel"]), vec![]);
+        assert_eq!(
+            optimise_with("+-<>", &["fold", "cancel"], false),
+            vec![Op::Move(-1), Op::Move(1)]
+        );
+    }
+
+    #[test]
+    fn clear_loops_are_replaced() {
+        assert_eq!(
+            optimise("+[-]>+[+]", &["clear-loops"]),
+            vec![
+                Op::Add(1),
+                Op::SetZero,
+                Op::Move(1),
+                Op::Add(1),
+                Op::SetZero
+            ]
+        );
+        assert_eq!(
+            optimise("+[--]", &["fold", "clear-loops"]),
+            vec![
+                Op::Add(1),
+                Op::LoopStart { end: 3 },
+                Op::Add(-2),
+                Op::LoopEnd { start: 1 }
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_loops_are_replaced() {
+        assert_eq!(
+            optimise("+[<]>[>]", &["scan-loops"]),
+            vec![Op::Add(1), Op::ScanLeft, Op::Move(1), Op::ScanRight]
+        );
+    }
+
+    #[test]
+    fn mul_loops_are_replaced() {
+        assert_eq!(
+            optimise("+[->++>+<<]", &["fold", "mul-loops"]),
+            vec![
+                Op::Add(1),
+                Op::MulAdd {
+                    offset: 1,
+                    factor: 2
+                },
+                Op::MulAdd {
+                    offset: 2,
+                    factor: 1
+                },
+                Op::SetZero
+            ]
+        );
+
+        // The loop counter goes up, so this is not a multiply loop
+        assert_eq!(optimise("+[+>+<]", &["fold", "mul-loops"]).len(), 7);
+    }
+
+    #[test]
+    fn dead_loops_are_removed() {
+        assert_eq!(
+            optimise("[comment.,][more]+[-][>]<", &["dead-loops"]),
+            vec![
+                Op::Add(1),
+                Op::LoopStart { end: 3 },
+                Op::Add(-1),
+                Op::LoopEnd { start: 1 },
+                Op::Move(-1)
+            ]
+        );
+    }
+
+    #[test]
+    fn replaced_loops_keep_their_span() {
+        let program = BFProgram::from_str("span.bf", "+[-]").unwrap();
+        let mut manager = PassManager::new();
+        manager.set_enabled("clear-loops", true).unwrap();
+
+        let ir = manager.run(IrProgram::lower(&program));
+        assert_eq!(ir.ops()[1].span(), Span::new(1, 4));
+    }
+
+    #[test]
+    fn opt_levels_enable_passes() {
+        assert!(PassManager::with_opt_level(0).enabled_passes().is_empty());
+        assert_eq!(
+            PassManager::with_opt_level(1).enabled_passes(),
+            vec!["fold", "cancel"]
+        );
+        assert_eq!(
+            PassManager::with_opt_level(2).enabled_passes(),
+            PassManager::PASS_NAMES.to_vec()
+        );
+    }
+
+    #[test]
+    fn unknown_passes_are_rejected() {
+        let mut manager = PassManager::new();
+
+        assert_eq!(
+            manager.set_enabled("nope", true),
+            Err(UnknownPass("nope".to_string()))
+        );
+    }
+
+    #[test]
+    fn custom_passes_can_be_added() {
+        struct DropOutput;
+
+        impl Pass for DropOutput {
+            fn name(&self) -> &'static str {
+                "drop-output"
+            }
+
+            fn run(&self, ops: Vec<crate::ir::IrInstruction>) -> Vec<crate::ir::IrInstruction> {
+                ops.into_iter().filter(|i| i.op() != Op::Output).collect()
+            }
+        }
+
+        let program = BFProgram::from_str("custom.bf", "+.").unwrap();
+        let mut manager = PassManager::new();
+        manager.add_pass(Box::new(DropOutput));
+
+        assert_eq!(manager.run(IrProgram::lower(&program)).len(), 1);
+    }
+}
@@ -1,35 +1,396 @@
 extern crate clap;
-use clap::{App, Arg};
+use bft_interp::limits::ResourceLimits;
+use bft_interp::tape::{GrowthPolicy, TapeBoundary, TapeGrowth};
+use bft_interp::{EofBehavior, OverflowPolicy};
+use bft_types::passes::PassManager;
+use bft_types::Extensions;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::time::Duration;
 
-pub fn get_filename_and_cells() -> (String, usize) {
+/// The options given on the command line
+pub struct Options {
+    pub program: String,
+    pub cells: usize,
+    pub cell_width: u32,
+    pub boundary: TapeBoundary,
+    pub growth: TapeGrowth,
+    pub overflow: OverflowPolicy,
+    pub eof: EofBehavior,
+    pub extensions: Extensions,
+    pub limits: ResourceLimits,
+    pub trace: Option<String>,
+    pub coverage: Option<String>,
+    pub load_state: Option<String>,
+    pub save_state: Option<String>,
+    pub detect_loops: bool,
+    pub opt_level: u32,
+    pub passes: Vec<String>,
+    pub no_passes: Vec<String>,
+}
+
+impl Options {
+    /// Build the PassManager selected by the optimisation level and the
+    /// individual pass flags, a pass given to both --pass and --no-pass is
+    /// disabled.  Moves are only folded across a change of direction if the
+    /// head can not fall off the tape.
+    pub fn pass_manager(&self) -> PassManager {
+        let mut manager = PassManager::with_opt_level(self.opt_level);
+        let grows_without_limit = self.boundary == TapeBoundary::Grow
+            && self.growth.bidirectional
+            && self.growth.max_size.is_none()
+            && self.limits.max_tape.is_none();
+        manager.set_skip_cells(self.boundary == TapeBoundary::Wrap || grows_without_limit);
+
+        // The names were checked against the known passes by clap
+        for name in &self.passes {
+            manager.set_enabled(name, true).unwrap();
+        }
+        for name in &self.no_passes {
+            manager.set_enabled(name, false).unwrap();
+        }
+
+        manager
+    }
+}
+
+/// What the command line asked bft to do
+pub enum Command {
+    /// Run the program
+    Run(Options),
+    /// Run the program in the debugger, reading its input from the file
+    Debug(Options, Option<String>),
+    /// Run the program and report how often each command ran, listing the
+    /// given number of hottest loops and, if asked, every command's count
+    Profile(Options, usize, bool),
+}
+
+pub fn get_command() -> Command {
     let matches = App::new("Brain Fuck Interpretor")
         .version("1.0")
         .author("John Ward <john@johnward.net>")
         .about("Does awesome things")
-        .arg(
-            Arg::with_name("cells")
-                .short("c")
-                .long("cells")
-                .value_name("CELLS")
-                .help("Sets the number of cells")
-                .takes_value(true),
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&machine_args())
+        .args(&limit_args())
+        .args(&optimiser_args())
+        .arg(trace_arg())
+        .arg(coverage_arg())
+        .arg(detect_loops_arg())
+        .args(&state_args())
+        .arg(program_arg())
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs a program, the same as leaving out the subcommand")
+                .args(&machine_args())
+                .args(&limit_args())
+                .args(&optimiser_args())
+                .arg(trace_arg())
+                .arg(coverage_arg())
+                .arg(detect_loops_arg())
+                .args(&state_args())
+                .arg(program_arg()),
         )
-        .arg(
-            Arg::with_name("PROGRAM")
-                .help("Sets the input file to use")
-                .required(true)
-                .index(1),
+        .subcommand(
+            SubCommand::with_name("debug")
+                .about("Runs a program in an interactive debugger")
+                .args(&machine_args())
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .value_name("FILE")
+                        .help("Sets the file the program reads its input from, stdin is used for debugger commands"),
+                )
+                .arg(program_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("profile")
+                .about("Runs a program and reports how often each command ran")
+                .args(&machine_args())
+                .args(&limit_args())
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .value_name("LOOPS")
+                        .help("Sets how many of the hottest loops to list")
+                        .validator(is_number)
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::with_name("instructions")
+                        .long("instructions")
+                        .help("Lists how often every command ran"),
+                )
+                .arg(program_arg()),
         )
         .get_matches();
 
-    let mut cell_size = 30000;
-    let cells = matches.value_of("cells").unwrap();
+    match matches.subcommand() {
+        ("run", Some(sub_matches)) => Command::Run(get_options(sub_matches)),
+        ("debug", Some(sub_matches)) => Command::Debug(
+            get_options(sub_matches),
+            sub_matches.value_of("input").map(String::from),
+        ),
+        ("profile", Some(sub_matches)) => Command::Profile(
+            get_options(sub_matches),
+            sub_matches.value_of("top").unwrap().parse().unwrap(),
+            sub_matches.is_present("instructions"),
+        ),
+        _ => Command::Run(get_options(&matches)),
+    }
+}
+
+/// The arguments which set up the virtual machine
+fn machine_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("debug-dump")
+            .long("debug-dump")
+            .help("Treats '#' as a command which dumps the tape to stderr, rather than a comment"),
+        Arg::with_name("cells")
+            .short("c")
+            .long("cells")
+            .value_name("CELLS")
+            .help("Sets the number of cells, the default is 30000")
+            .takes_value(true)
+            .validator(|v| match v.parse::<usize>() {
+                Ok(cells) if cells > 0 => Ok(()),
+                _ => Err(String::from("expected a number of cells greater than zero")),
+            }),
+        Arg::with_name("cell-width")
+            .long("cell-width")
+            .value_name("BITS")
+            .help("Sets the number of bits in each cell")
+            .possible_values(&["8", "16", "32", "64"])
+            .default_value("8"),
+        Arg::with_name("boundary")
+            .long("boundary")
+            .value_name("POLICY")
+            .help("Sets what happens when the head runs off the end of the tape")
+            .possible_values(&["error", "wrap", "clamp", "grow"])
+            .conflicts_with("grow"),
+        Arg::with_name("grow")
+            .short("g")
+            .long("grow")
+            .help("Lets the tape grow when the head runs off its right hand end, the same as --boundary grow"),
+        Arg::with_name("grow-left")
+            .long("grow-left")
+            .help("Lets a growing tape also grow off its left hand end"),
+        Arg::with_name("growth")
+            .long("growth")
+            .value_name("CELLS")
+            .help("Sets how many cells to add when the tape grows, or 'double' (the default)")
+            .validator(|v| parse_growth(&v).map(|_| ())),
+        Arg::with_name("max-cells")
+            .long("max-cells")
+            .value_name("CELLS")
+            .help("Sets the most cells a growing tape may have, going past it is a tape size error")
+            .validator(|v| {
+                v.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| String::from("expected a number of cells"))
+            }),
+        Arg::with_name("overflow")
+            .long("overflow")
+            .value_name("POLICY")
+            .help("Sets what happens when a cell overflows")
+            .possible_values(&["wrap", "saturate", "trap"])
+            .default_value("wrap"),
+        Arg::with_name("eof")
+            .long("eof")
+            .value_name("BEHAVIOUR")
+            .help("Sets what ',' stores in the cell at the end of the input")
+            .possible_values(&["unchanged", "zero", "minus-one"])
+            .default_value("unchanged"),
+    ]
+}
+
+/// The arguments which limit what the program may do
+fn limit_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("max-steps")
+            .long("max-steps")
+            .value_name("STEPS")
+            .help("Stops the program after it has run this many commands")
+            .validator(is_number),
+        Arg::with_name("timeout")
+            .long("timeout")
+            .value_name("SECONDS")
+            .help("Stops the program after it has run for this long")
+            .validator(|v| parse_timeout(&v).map(|_| ())),
+        Arg::with_name("max-output")
+            .long("max-output")
+            .value_name("BYTES")
+            .help("Stops the program if it outputs more than this many bytes")
+            .validator(is_number),
+        Arg::with_name("max-tape")
+            .long("max-tape")
+            .value_name("CELLS")
+            .help("Stops the program if its tape would grow past this many cells, with --max-cells the smaller one applies and a tie is reported as this limit")
+            .validator(is_number),
+    ]
+}
+
+/// The arguments which choose the optimisation passes
+fn optimiser_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("opt-level")
+            .short("O")
+            .long("opt-level")
+            .value_name("LEVEL")
+            .help("Sets the optimisation level, 0 runs the plain interpreter")
+            .possible_values(&["0", "1", "2"])
+            .default_value("0"),
+        Arg::with_name("pass")
+            .long("pass")
+            .value_name("PASS")
+            .help("Enables an optimisation pass")
+            .possible_values(PassManager::PASS_NAMES)
+            .multiple(true)
+            .number_of_values(1),
+        Arg::with_name("no-pass")
+            .long("no-pass")
+            .value_name("PASS")
+            .help("Disables an optimisation pass")
+            .possible_values(PassManager::PASS_NAMES)
+            .multiple(true)
+            .number_of_values(1),
+    ]
+}
 
-    if cells.parse::<usize>().is_ok() && cells.parse::<usize>().unwrap() > cell_size {
-        cell_size = cells.parse::<usize>().unwrap();
+fn trace_arg() -> Arg<'static, 'static> {
+    Arg::with_name("trace")
+        .long("trace")
+        .value_name("FILE")
+        .help("Writes a JSON Lines trace of every command run to the file")
+}
+
+fn coverage_arg() -> Arg<'static, 'static> {
+    Arg::with_name("coverage")
+        .long("coverage")
+        .value_name("FILE")
+        .help("Writes an lcov report of the lines run to the file, and lists the loops which never ran")
+}
+
+fn detect_loops_arg() -> Arg<'static, 'static> {
+    Arg::with_name("detect-loops")
+        .long("detect-loops")
+        .help("Stops the program if a loop gets back to a state it has been in before, so would never finish")
+}
+
+/// The arguments which save and load the state of the machine
+fn state_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("load-state")
+            .long("load-state")
+            .value_name("FILE")
+            .help("Carries on from a state saved by --save-state, the input starts again from the beginning"),
+        Arg::with_name("save-state")
+            .long("save-state")
+            .value_name("FILE")
+            .help("Saves the state of the machine to the file when the program stops"),
+    ]
+}
+
+fn program_arg() -> Arg<'static, 'static> {
+    Arg::with_name("PROGRAM")
+        .help("Sets the input file to use")
+        .required(true)
+        .index(1)
+}
+
+/// Read the options from the matches for the top level or a subcommand,
+/// the optimiser arguments are left at their defaults if there are none
+fn get_options(matches: &ArgMatches) -> Options {
+    let cell_size = matches
+        .value_of("cells")
+        .map_or(30000, |cells| cells.parse().unwrap());
+
+    let values = |name| -> Vec<String> {
+        matches
+            .values_of(name)
+            .map(|values| values.map(String::from).collect())
+            .unwrap_or_default()
+    };
+
+    Options {
+        program: String::from(matches.value_of("PROGRAM").unwrap_or("default.conf")),
+        cells: cell_size,
+        cell_width: matches.value_of("cell-width").unwrap().parse().unwrap(),
+        boundary: match matches.value_of("boundary") {
+            Some("wrap") => TapeBoundary::Wrap,
+            Some("clamp") => TapeBoundary::Clamp,
+            Some("grow") => TapeBoundary::Grow,
+            _ if matches.is_present("grow") => TapeBoundary::Grow,
+            _ => TapeBoundary::Error,
+        },
+        growth: TapeGrowth {
+            policy: parse_growth(matches.value_of("growth").unwrap_or("double")).unwrap(),
+            max_size: matches
+                .value_of("max-cells")
+                .map(|v| v.parse::<usize>().unwrap()),
+            bidirectional: matches.is_present("grow-left"),
+        },
+        overflow: match matches.value_of("overflow") {
+            Some("saturate") => OverflowPolicy::Saturate,
+            Some("trap") => OverflowPolicy::Trap,
+            _ => OverflowPolicy::Wrap,
+        },
+        eof: match matches.value_of("eof") {
+            Some("zero") => EofBehavior::Zero,
+            Some("minus-one") => EofBehavior::MinusOne,
+            _ => EofBehavior::Unchanged,
+        },
+        extensions: Extensions {
+            debug_dump: matches.is_present("debug-dump"),
+        },
+        limits: ResourceLimits {
+            max_steps: matches.value_of("max-steps").map(|v| v.parse().unwrap()),
+            max_time: matches
+                .value_of("timeout")
+                .map(|v| parse_timeout(v).unwrap()),
+            max_output: matches.value_of("max-output").map(|v| v.parse().unwrap()),
+            max_tape: matches.value_of("max-tape").map(|v| v.parse().unwrap()),
+        },
+        trace: matches.value_of("trace").map(String::from),
+        coverage: matches.value_of("coverage").map(String::from),
+        load_state: matches.value_of("load-state").map(String::from),
+        save_state: matches.value_of("save-state").map(String::from),
+        detect_loops: matches.is_present("detect-loops"),
+        opt_level: matches
+            .value_of("opt-level")
+            .unwrap_or("0")
+            .parse()
+            .unwrap(),
+        passes: values("pass"),
+        no_passes: values("no-pass"),
+    }
+}
+
+/// Parse the --growth value, either "double" or a number of cells
+fn parse_growth(value: &str) -> Result<GrowthPolicy, String> {
+    match value {
+        "double" => Ok(GrowthPolicy::Double),
+        _ => match value.parse::<usize>() {
+            Ok(cells) if cells > 0 => Ok(GrowthPolicy::Chunk(cells)),
+            _ => Err(String::from("expected 'double' or a number of cells")),
+        },
     }
+}
 
-    let program_name = String::from(matches.value_of("PROGRAM").unwrap_or("default.conf"));
+/// Parse the value of --timeout, a number of seconds which fits in a
+/// Duration
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| String::from("expected a number of seconds"))
+}
 
-    (program_name, cell_size)
+/// Check that a value is a whole number
+fn is_number(value: String) -> Result<(), String> {
+    value
+        .parse::<u64>()
+        .map(|_| ())
+        .map_err(|_| String::from("expected a whole number"))
 }
@@ -0,0 +1,391 @@
+//! An interactive debugger for BrainFuck programs
+//!
+//! The debugger reads one command per line and drives a `BFVirtualMachine`
+//! with its step API.  Breakpoints are set by the line and column of a
+//! command, which are zero-based just as they are printed in
+//! `[Line x, Col y] c` instructions.
+//!
+//! The machine records what each command changes, so the debugger can also
+//! run backwards, see `bft_interp::history` for what can and can not be
+//! undone.
+
+use bft_interp::watch::WatchCondition;
+use bft_interp::{BFVirtualMachine, CellKind, StepOutcome};
+use bft_types::BFCommand;
+use std::io::{self, BufRead, Read, Write};
+
+/// The number of cells printed either side of the head by `tape`
+const TAPE_WINDOW: usize = 8;
+
+/// The number of commands which can be stepped back over
+const HISTORY_SIZE: usize = 1_000_000;
+
+const HELP: &str = "\
+Commands:
+  break LINE:COL     set a breakpoint on the command at LINE:COL
+  delete LINE:COL    remove a breakpoint
+  breakpoints        list the breakpoints
+  watch CELL [VALUE] stop when the cell changes, or changes to VALUE
+  unwatch CELL       remove the watchpoints on a cell
+  step [N]           run N commands, 1 if N is left out
+  next               run the next command, running a whole loop if it is a '['
+  continue           run until a breakpoint or the end of the program
+  reverse-step [N]   undo N commands, 1 if N is left out
+  reverse-continue   undo commands until a breakpoint or the start of the recording
+  tape [N]           print N cells either side of the head
+  print              print the next command
+  help               print this help
+  quit               leave the debugger
+";
+
+pub struct Debugger<'a, T, R> {
+    virtual_machine: BFVirtualMachine<'a, T>,
+    input: R,
+    breakpoints: Vec<usize>,
+}
+
+impl<'a, T, R> Debugger<'a, T, R>
+where
+    T: CellKind,
+    R: Read,
+{
+    /// Create a debugger for the virtual machine, the program reads its
+    /// input from input
+    pub fn new(mut virtual_machine: BFVirtualMachine<'a, T>, input: R) -> Debugger<'a, T, R> {
+        virtual_machine.set_recording(Some(HISTORY_SIZE));
+        Debugger {
+            virtual_machine,
+            input,
+            breakpoints: Vec::new(),
+        }
+    }
+
+    /// Read commands until quit or the end of the commands.  Messages from
+    /// the debugger and the output of the program both go to out.
+    pub fn run<C, W>(&mut self, commands: C, mut out: W) -> io::Result<()>
+    where
+        C: BufRead,
+        W: Write,
+    {
+        self.print_instruction(&mut out)?;
+        write!(out, "(bft) ")?;
+        out.flush()?;
+
+        for line in commands.lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+
+            match (words.next(), words.next()) {
+                (None, _) => (),
+                (Some("b"), Some(location)) | (Some("break"), Some(location)) => {
+                    self.add_breakpoint(location, &mut out)?
+                }
+                (Some("d"), Some(location)) | (Some("delete"), Some(location)) => {
+                    self.delete_breakpoint(location, &mut out)?
+                }
+                (Some("breakpoints"), None) => self.print_breakpoints(&mut out)?,
+                (Some("w"), Some(index)) | (Some("watch"), Some(index)) => {
+                    self.add_watchpoint(index, words.next(), &mut out)?
+                }
+                (Some("unwatch"), Some(index)) => match index.parse() {
+                    Ok(index) => self.virtual_machine.remove_watchpoints(index),
+                    Err(_) => writeln!(out, "Expected a cell number")?,
+                },
+                (Some("s"), count) | (Some("step"), count) => match parse_count(count, 1) {
+                    Some(count) => self.step(count, &mut out)?,
+                    None => writeln!(out, "Expected a number of steps")?,
+                },
+                (Some("n"), None) | (Some("next"), None) => self.next(&mut out)?,
+                (Some("c"), None) | (Some("continue"), None) => self.cont(&mut out)?,
+                (Some("rs"), count) | (Some("reverse-step"), count) => {
+                    match parse_count(count, 1) {
+                        Some(count) => self.reverse_step(count, &mut out)?,
+                        None => writeln!(out, "Expected a number of steps")?,
+                    }
+                }
+                (Some("rc"), None) | (Some("reverse-continue"), None) => {
+                    self.reverse_cont(&mut out)?
+                }
+                (Some("t"), count) | (Some("tape"), count) => {
+                    match parse_count(count, TAPE_WINDOW) {
+                        Some(window) => self.print_tape(window, &mut out)?,
+                        None => writeln!(out, "Expected a number of cells")?,
+                    }
+                }
+                (Some("p"), None) | (Some("print"), None) => self.print_instruction(&mut out)?,
+                (Some("h"), None) | (Some("help"), None) => write!(out, "{}", HELP)?,
+                (Some("q"), None) | (Some("quit"), None) => return Ok(()),
+                _ => writeln!(out, "Unknown command '{}', try 'help'", line.trim())?,
+            }
+
+            write!(out, "(bft) ")?;
+            out.flush()?;
+        }
+
+        writeln!(out)
+    }
+
+    /// Find the index of the command at a "line:col" location
+    fn find_location(&self, location: &str) -> Option<usize> {
+        let mut parts = location.splitn(2, ':');
+        let line = parts.next()?.parse::<usize>().ok()?;
+        let column = parts.next()?.parse::<usize>().ok()?;
+
+        self.virtual_machine
+            .program()
+            .commands()
+            .iter()
+            .position(|instruction| {
+                instruction.line_number() == line && instruction.column_number() == column
+            })
+    }
+
+    fn add_breakpoint(&mut self, location: &str, out: &mut impl Write) -> io::Result<()> {
+        match self.find_location(location) {
+            Some(index) => {
+                if !self.breakpoints.contains(&index) {
+                    self.breakpoints.push(index);
+                }
+                writeln!(
+                    out,
+                    "Breakpoint at {}",
+                    self.virtual_machine.program().commands()[index]
+                )
+            }
+            None => writeln!(out, "No command at '{}', expected LINE:COL", location),
+        }
+    }
+
+    fn delete_breakpoint(&mut self, location: &str, out: &mut impl Write) -> io::Result<()> {
+        match self.find_location(location) {
+            Some(index) if self.breakpoints.contains(&index) => {
+                self.breakpoints.retain(|&breakpoint| breakpoint != index);
+                writeln!(out, "Deleted breakpoint at {}", location)
+            }
+            _ => writeln!(out, "No breakpoint at '{}'", location),
+        }
+    }
+
+    fn print_breakpoints(&self, out: &mut impl Write) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            return writeln!(out, "No breakpoints");
+        }
+
+        for &index in &self.breakpoints {
+            writeln!(out, "{}", self.virtual_machine.program().commands()[index])?;
+        }
+
+        Ok(())
+    }
+
+    fn add_watchpoint(
+        &mut self,
+        index: &str,
+        value: Option<&str>,
+        out: &mut impl Write,
+    ) -> io::Result<()> {
+        let index = match index.parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => return writeln!(out, "Expected a cell number"),
+        };
+
+        let condition = match value.map(str::parse::<T>) {
+            None => WatchCondition::Changed,
+            Some(Ok(value)) => WatchCondition::Equals(value),
+            Some(Err(_)) => return writeln!(out, "Expected a {} value", T::NAME),
+        };
+
+        self.virtual_machine.add_watchpoint(index, condition);
+        writeln!(out, "Watching #{}", index)
+    }
+
+    fn step(&mut self, count: usize, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..count {
+            let outcome = self.virtual_machine.step(&mut self.input, out);
+            if !matches!(outcome, StepOutcome::Continued) {
+                return self.report(outcome, out);
+            }
+        }
+
+        self.print_instruction(out)
+    }
+
+    /// Step, but treat a whole loop as one step
+    fn next(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let program_counter = self.virtual_machine.program_counter();
+        let loop_end = match self.virtual_machine.current_instruction() {
+            Some(instruction) if instruction.get_command() == BFCommand::IfZeroJumpForward => self
+                .virtual_machine
+                .program()
+                .matching_bracket(program_counter),
+            _ => None,
+        };
+
+        match loop_end {
+            Some(loop_end) => {
+                let breakpoints = &self.breakpoints;
+                let outcome = self.virtual_machine.run_until(&mut self.input, out, |vm| {
+                    vm.program_counter() == loop_end + 1
+                        || breakpoints.contains(&vm.program_counter())
+                });
+                self.report(outcome, out)
+            }
+            None => self.step(1, out),
+        }
+    }
+
+    /// Run until a breakpoint or the end of the program
+    fn cont(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let breakpoints = &self.breakpoints;
+        let outcome = self.virtual_machine.run_until(&mut self.input, out, |vm| {
+            breakpoints.contains(&vm.program_counter())
+        });
+        self.report(outcome, out)
+    }
+
+    /// Undo count commands
+    fn reverse_step(&mut self, count: usize, out: &mut impl Write) -> io::Result<()> {
+        for _ in 0..count {
+            if self.virtual_machine.step_back().is_none() {
+                writeln!(out, "Reached the start of the recording")?;
+                break;
+            }
+        }
+
+        self.print_instruction(out)
+    }
+
+    /// Undo commands until a breakpoint or the start of the recording
+    fn reverse_cont(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let breakpoints = &self.breakpoints;
+        if !self
+            .virtual_machine
+            .step_back_until(|vm| breakpoints.contains(&vm.program_counter()))
+        {
+            writeln!(out, "Reached the start of the recording")?;
+        }
+
+        self.print_instruction(out)
+    }
+
+    /// Say why running stopped
+    fn report(&self, outcome: StepOutcome, out: &mut impl Write) -> io::Result<()> {
+        match outcome {
+            StepOutcome::Continued => self.print_instruction(out),
+            StepOutcome::Halted => writeln!(out, "\nProgram finished"),
+            StepOutcome::Error(e) => writeln!(out, "\nbft: {}", e),
+            StepOutcome::Watchpoint { index, instruction } => {
+                writeln!(
+                    out,
+                    "Cell #{} is now {} after {}",
+                    index,
+                    self.virtual_machine.tape_slice(index..index + 1)[0],
+                    instruction
+                )?;
+                self.print_instruction(out)
+            }
+        }
+    }
+
+    fn print_instruction(&self, out: &mut impl Write) -> io::Result<()> {
+        match self.virtual_machine.current_instruction() {
+            Some(instruction) => writeln!(out, "{}", instruction),
+            None => writeln!(out, "Program finished"),
+        }
+    }
+
+    /// Print the cells either side of the head, with the head in brackets
+    fn print_tape(&self, window: usize, out: &mut impl Write) -> io::Result<()> {
+        self.virtual_machine.tape().write_window(out, window)
+    }
+}
+
+/// Parse an optional count, using default if it is missing
+fn parse_count(count: Option<&str>, default: usize) -> Option<usize> {
+    match count {
+        Some(count) => count.parse().ok(),
+        None => Some(default),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use bft_interp::BFVirtualMachine;
+    use bft_types::BFProgram;
+    use std::io::Cursor;
+
+    fn run_commands(source: &str, commands: &str) -> String {
+        let program = BFProgram::from_str("debug.bf", source).unwrap();
+        let virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 10);
+        let mut debugger = Debugger::new(virtual_machine, Cursor::new(b"A".to_vec()));
+        let mut out = Vec::new();
+
+        debugger.run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let out = run_commands("++\n>+++[-]\n.", "b 1:4\nc\nt 1\nc\n");
+
+        assert!(out.contains("Breakpoint at [Line 1, Col 4] [\n"));
+        assert!(out.contains("(bft) [Line 1, Col 4] [\n"));
+        assert!(out.contains("#0=2 [#1=3] #2=0 \n"));
+        assert!(out.ends_with("\u{0}\nProgram finished\n(bft) \n"));
+    }
+
+    #[test]
+    fn next_runs_a_whole_loop() {
+        let out = run_commands("+++[-],.", "s 3\nn\nt 0\nnext\nc\nq\n");
+
+        assert!(out.contains("(bft) [Line 0, Col 3] [\n"));
+        assert!(out.contains("(bft) [Line 0, Col 6] ,\n"));
+        assert!(out.contains("[#0=0] \n"));
+        assert!(out.contains("(bft) A\nProgram finished\n"));
+    }
+
+    #[test]
+    fn bad_commands_are_reported() {
+        let out = run_commands("+", "b 5:5\nd 0:0\nstep x\nfly\n");
+
+        assert!(out.contains("No command at '5:5', expected LINE:COL"));
+        assert!(out.contains("No breakpoint at '0:0'"));
+        assert!(out.contains("Expected a number of steps"));
+        assert!(out.contains("Unknown command 'fly', try 'help'"));
+    }
+
+    #[test]
+    fn stops_at_watchpoints() {
+        let out = run_commands(
+            "++>+++[<+>-]",
+            "watch 0 4
+w 1
+c
+unwatch 1
+c
+c
+",
+        );
+
+        assert!(out.contains("Cell #1 is now 1 after [Line 0, Col 3] +\n"));
+        assert!(out.contains("Cell #0 is now 4 after [Line 0, Col 8] +\n"));
+        assert!(out.ends_with("Program finished\n(bft) \n"));
+    }
+
+    #[test]
+    fn runs_backwards() {
+        let out = run_commands(
+            ",+>++[-]<.",
+            "b 0:6\nc\nc\nc\nt 1\nrc\nt 1\nrs 2\nt 1\nrs 9\nd 0:6\nc\n",
+        );
+
+        assert!(out.contains("(bft) B\nProgram finished\n(bft) [#0=66] #1=0 \n"));
+        assert!(out.contains("(bft) [Line 0, Col 6] -\n(bft) #0=66 [#1=1] #2=0 \n"));
+        assert!(out.contains("(bft) [Line 0, Col 6] -\n(bft) #0=66 [#1=2] #2=0 \n"));
+        assert!(out.contains("(bft) Reached the start of the recording\n[Line 0, Col 0] ,\n"));
+
+        // The input is read again after going back over the `,`
+        assert!(out.ends_with("(bft) B\nProgram finished\n(bft) \n"));
+    }
+}
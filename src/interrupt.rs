@@ -0,0 +1,39 @@
+//! Stopping a running program with Ctrl-C
+//!
+//! The first SIGINT cancels the virtual machine's token, so that it stops
+//! at the next check and `bft` can say where the program was.  A second
+//! SIGINT exits straight away, for a program stuck waiting for input.
+
+use bft_interp::cancel::CancelToken;
+use std::sync::OnceLock;
+
+/// The token cancelled by the signal handler
+static TOKEN: OnceLock<CancelToken> = OnceLock::new();
+
+/// The exit status of a program killed by SIGINT
+pub const INTERRUPTED: i32 = 130;
+
+/// Install the SIGINT handler, returning the token it cancels
+pub fn install() -> CancelToken {
+    let token = TOKEN.get_or_init(CancelToken::new).clone();
+
+    #[cfg(unix)]
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = on_interrupt;
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+
+    token
+}
+
+/// Only storing to and loading from atomics is done here, which is safe in
+/// a signal handler
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    if let Some(token) = TOKEN.get() {
+        if token.is_cancelled() {
+            unsafe { libc::_exit(INTERRUPTED) };
+        }
+        token.cancel();
+    }
+}
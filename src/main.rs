@@ -1,76 +1,346 @@
-use bft_interp::BFVirtualMachine;
+use bft_interp::ir::IrVirtualMachine;
+use bft_interp::profile::Profile;
+use bft_interp::snapshot::{SnapshotError, VmState};
+use bft_interp::tape::{Tape, TapeBoundary};
+use bft_interp::OverflowPolicy;
+use bft_interp::{BFVirtualMachine, CellKind, VMError};
+use bft_types::ir::IrProgram;
+use bft_types::passes::PassManager;
 use bft_types::BFProgram;
-use std::io::Cursor;
-//use std::env::args;
-use std::io::Write;
+use debugger::Debugger;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::result::Result;
 
 mod cli;
+mod debugger;
+mod interrupt;
+mod profiler;
+
+/// The number of cells printed either side of the head when a program fails
+const ERROR_WINDOW: usize = 8;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (filename, cells_number) = cli::get_filename_and_cells();
+    let command = cli::get_command();
+    let options = match &command {
+        cli::Command::Run(options)
+        | cli::Command::Debug(options, _)
+        | cli::Command::Profile(options, _, _) => options,
+    };
 
-    let mut buff = Cursor::new(vec![15, 23]);
+    let program = match BFProgram::try_new_with(&options.program, options.extensions) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("bft: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    println!("Filename {}", filename);
-    println!("Number of Cells: {}", cells_number);
+    if let Some(max_tape) = options.limits.max_tape {
+        if options.cells > max_tape {
+            eprintln!(
+                "bft: the tape starts with {} cells, more than --max-tape allows, use --cells to start smaller",
+                options.cells
+            );
+            std::process::exit(1);
+        }
+    }
 
-    let program = BFProgram::new(filename);
+    match &command {
+        cli::Command::Debug(_, input) => {
+            let res = match options.cell_width {
+                16 => debug::<u16>(&program, options, input),
+                32 => debug::<u32>(&program, options, input),
+                64 => debug::<u64>(&program, options, input),
+                _ => debug::<u8>(&program, options, input),
+            };
+            if let Err(e) = res {
+                eprintln!("bft: {}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        cli::Command::Profile(_, top, per_instruction) => {
+            let res = match options.cell_width {
+                16 => profile::<u16>(&program, options, *top, *per_instruction),
+                32 => profile::<u32>(&program, options, *top, *per_instruction),
+                64 => profile::<u64>(&program, options, *top, *per_instruction),
+                _ => profile::<u8>(&program, options, *top, *per_instruction),
+            };
+            if let Err(e) = res {
+                exit_with(&e);
+            }
+            return Ok(());
+        }
+        cli::Command::Run(_) => (),
+    }
 
-    let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 30000);
+    let pass_manager = options.pass_manager();
 
-    //virtual_machine.interpret
+    // The passes assume cells wrap, and multiply loops on a clamped tape
+    // would change cells the plain machine never reaches.  Moves which could
+    // fall off the tape are kept apart by pass_manager.
+    if !pass_manager.enabled_passes().is_empty()
+        && (options.boundary == TapeBoundary::Clamp || options.overflow != OverflowPolicy::Wrap)
+    {
+        eprintln!("bft: optimisation passes need wrapping cells and an unclamped tape");
+        std::process::exit(1);
+    }
 
-    println!("Current Cell: {}", virtual_machine.get_current_cell());
+    // The trace, coverage and saved states are of the commands in the
+    // source, which the optimised program no longer has
+    if !pass_manager.enabled_passes().is_empty() {
+        if options.trace.is_some() {
+            eprintln!("bft: --trace can not be used with optimisation passes");
+            std::process::exit(1);
+        }
+        if options.coverage.is_some() {
+            eprintln!("bft: --coverage can not be used with optimisation passes");
+            std::process::exit(1);
+        }
+        if options.detect_loops {
+            eprintln!("bft: --detect-loops can not be used with optimisation passes");
+            std::process::exit(1);
+        }
+        if options.load_state.is_some() || options.save_state.is_some() {
+            eprintln!("bft: states can not be saved or loaded with optimisation passes");
+            std::process::exit(1);
+        }
+    }
+
+    let files = RunFiles {
+        trace: options
+            .trace
+            .as_ref()
+            .map(|path| create_file(path, "trace")),
+        coverage: options
+            .coverage
+            .as_ref()
+            .map(|path| create_file(path, "coverage")),
+    };
+
+    let res = match options.cell_width {
+        16 => run::<u16>(&program, options, &pass_manager, files),
+        32 => run::<u32>(&program, options, &pass_manager, files),
+        64 => run::<u64>(&program, options, &pass_manager, files),
+        _ => run::<u8>(&program, options, &pass_manager, files),
+    };
 
-    let interp = virtual_machine.move_head_right();
+    if let Err(e) = res {
+        exit_with(&e);
+    }
+    Ok(())
+}
 
-    match interp {
-        Ok(_s) => println!("It's OK!!!"),
-        Err(_e) => println!("It's Not OK"),
+/// Exit after a program failed, the error has already been printed
+fn exit_with(error: &VMError) -> ! {
+    match error {
+        VMError::Cancelled(_) => std::process::exit(interrupt::INTERRUPTED),
+        _ => std::process::exit(1),
     }
+}
 
-    println!("Current Cell: {}", virtual_machine.get_current_cell());
+/// The files `run` writes as well as the program's output
+struct RunFiles {
+    trace: Option<BufWriter<File>>,
+    coverage: Option<BufWriter<File>>,
+}
 
-    match virtual_machine.input(&mut buff) {
-        Ok(_s) => println!("Written Correctly"),
-        Err(_e) => println!("Write Error"),
+/// Create one of the files written by `run`, exiting if that fails
+fn create_file(path: &str, what: &str) -> BufWriter<File> {
+    match File::create(path) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            eprintln!("bft: {}: unable to create {} file: {}", path, what, e);
+            std::process::exit(1);
+        }
     }
+}
 
-    let stdout = std::io::stdout();
-    let mut handle = stdout.lock();
+/// Create a plain virtual machine set up from the options
+fn virtual_machine<'a, T: CellKind>(
+    program: &'a BFProgram,
+    options: &cli::Options,
+) -> BFVirtualMachine<'a, T> {
+    let mut virtual_machine = BFVirtualMachine::new(program, false, options.cells);
+    virtual_machine.set_tape_boundary(options.boundary);
+    virtual_machine.set_tape_growth(options.growth);
+    virtual_machine.set_overflow_policy(options.overflow);
+    virtual_machine.set_eof_behavior(options.eof);
+    // The tape was checked against --max-tape by main
+    virtual_machine.set_resource_limits(options.limits).unwrap();
+    virtual_machine
+}
 
-    handle.write_all(b"hello world!!!!!!!!!\n")?;
+/// Restore a state saved by `save_state`, exiting if that fails
+fn load_state<T: CellKind>(virtual_machine: &mut BFVirtualMachine<T>, path: &str) {
+    let res = File::open(path)
+        .map_err(SnapshotError::from)
+        .and_then(|file| VmState::read_from(&mut BufReader::new(file)))
+        .and_then(|state| virtual_machine.restore(state));
+    if let Err(e) = res {
+        eprintln!("bft: {}: unable to load state: {}", path, e);
+        std::process::exit(1);
+    }
+}
 
-    match virtual_machine.output(&mut handle) {
-        // pass the borrow as mutable
-        Ok(_) => println!("Read Correctly"),
-        Err(_e) => println!("Read Error"),
+/// Save the state of the machine, so that a later run can carry on from it
+fn save_state<T: CellKind>(virtual_machine: &BFVirtualMachine<T>, path: &str) {
+    let mut file = create_file(path, "state");
+    let res = virtual_machine
+        .snapshot()
+        .write_to(&mut file)
+        .and_then(|()| file.flush());
+    if let Err(e) = res {
+        eprintln!("bft: {}: unable to save state: {}", path, e);
+    }
+}
+
+/// Run the program in the debugger, which reads its commands from stdin.
+/// The program reads its input from the input file, or has none.
+fn debug<T: CellKind>(
+    program: &BFProgram,
+    options: &cli::Options,
+    input: &Option<String>,
+) -> std::io::Result<()> {
+    let input: Box<dyn Read> = match input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(std::io::empty()),
     };
 
-    let res = virtual_machine.interpret(&mut buff, &mut handle);
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
 
-    match res {
-        Ok(()) => println!("All OK"),
-        Err(_e) => println!("Not OK"),
-    }
+    let mut debugger = Debugger::new(virtual_machine::<T>(program, options), input);
+    debugger.run(stdin.lock(), stdout.lock())
+}
 
-    let interp = virtual_machine.move_head_right();
+/// Run the program on stdin and stdout, then write the profile report to
+/// stderr
+fn profile<T: CellKind>(
+    program: &BFProgram,
+    options: &cli::Options,
+    top: usize,
+    per_instruction: bool,
+) -> Result<(), VMError> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut output = stdout.lock();
 
-    match interp {
-        Ok(_s) => println!("It's OK!!!"),
-        Err(_e) => println!("It's Not OK"),
+    let mut virtual_machine = virtual_machine::<T>(program, options);
+    virtual_machine.set_cancel_token(interrupt::install());
+    let mut profile = Profile::new(program);
+    let res = profile.run(&mut virtual_machine, stdin.lock(), &mut output);
+    if let Err(e) = &res {
+        print_error(program, e, virtual_machine.tape());
     }
 
-    println!("Current Cell: {}", virtual_machine.get_current_cell());
+    // The program was read from the file a moment ago, so reading it again
+    // should not fail, and if it does the report is just missing the source
+    let source = std::fs::read_to_string(program.filename()).unwrap_or_default();
+    let report = output.flush().and_then(|()| {
+        profiler::write_report(
+            &mut std::io::stderr().lock(),
+            &profile,
+            &source,
+            top,
+            per_instruction,
+        )
+    });
+    if let Err(e) = report {
+        eprintln!("bft: unable to write the profile: {}", e);
+    }
 
-    println!("---Virtual Machine---");
-    println!("{}", virtual_machine);
-    println!("---Virtual Machine---");
+    res
+}
 
-    let balanced = virtual_machine.has_matching_brackets().unwrap();
+/// Run the program on stdin and stdout with cells of type T, writing a
+/// trace of every command and a coverage report if there are files for them
+fn run<T: CellKind>(
+    program: &BFProgram,
+    options: &cli::Options,
+    pass_manager: &PassManager,
+    files: RunFiles,
+) -> Result<(), VMError> {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
 
-    println!("Has Matching Brackets: {}", balanced);
+    // With no passes enabled run the plain interpreter, so that the
+    // optimiser can be checked against it
+    if pass_manager.enabled_passes().is_empty() {
+        let mut virtual_machine = virtual_machine::<T>(program, options);
+        virtual_machine.set_cancel_token(interrupt::install());
+        if let Some(path) = &options.load_state {
+            load_state(&mut virtual_machine, path);
+        }
+        virtual_machine.set_loop_detection(options.detect_loops);
+        if let Some(trace) = files.trace {
+            virtual_machine.set_trace_writer(trace);
+        }
 
-    Ok(())
+        let res = match files.coverage {
+            Some(mut coverage) => {
+                let mut output = stdout.lock();
+                let mut profile = Profile::new(program);
+                let res = profile.run(&mut virtual_machine, stdin.lock(), &mut output);
+
+                let report = output
+                    .flush()
+                    .and_then(|()| profile.write_lcov(&mut coverage))
+                    .and_then(|()| coverage.flush())
+                    .and_then(|()| {
+                        profiler::write_coverage_summary(&mut std::io::stderr().lock(), &profile)
+                    });
+                if let Err(e) = report {
+                    eprintln!("bft: unable to write the coverage: {}", e);
+                }
+
+                res
+            }
+            None => virtual_machine.interpret(stdin.lock(), stdout.lock()),
+        };
+
+        if let Err(e) = &res {
+            print_error(program, e, virtual_machine.tape());
+        }
+
+        // Save even when the program failed, so that a run stopped by a
+        // resource limit or Ctrl-C can be carried on
+        if let Some(path) = &options.save_state {
+            save_state(&virtual_machine, path);
+        }
+
+        res
+    } else {
+        let ir = pass_manager.run(IrProgram::lower(program));
+        let mut virtual_machine: IrVirtualMachine<T> =
+            IrVirtualMachine::new(program, &ir, false, options.cells);
+        virtual_machine.set_tape_boundary(options.boundary);
+        virtual_machine.set_tape_growth(options.growth);
+        virtual_machine.set_eof_behavior(options.eof);
+        virtual_machine.set_resource_limits(options.limits).unwrap();
+        virtual_machine.set_cancel_token(interrupt::install());
+
+        let res = virtual_machine.interpret(stdin.lock(), stdout.lock());
+        if let Err(e) = &res {
+            print_error(program, e, virtual_machine.tape());
+        }
+        res
+    }
+}
+
+/// Say why the program stopped and where it was, with the cells around the
+/// head
+fn print_error<T: CellKind>(program: &BFProgram, error: &VMError, tape: &Tape<T>) {
+    let name = program.filename().display();
+    match error {
+        VMError::Cancelled(context) => eprintln!(
+            "\nbft: {}: interrupted at {}, head at #{}, after {} steps",
+            name, context.instruction, context.head, context.steps
+        ),
+        _ => eprintln!("\nbft: {}: {}", name, error),
+    }
+
+    // There is nowhere left to report a failure to write to stderr
+    let _ = tape.write_window(&mut std::io::stderr().lock(), ERROR_WINDOW);
 }
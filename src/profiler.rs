@@ -0,0 +1,135 @@
+//! The reports printed by `bft profile` and `bft run --coverage`
+
+use bft_interp::profile::Profile;
+use std::io::{self, Write};
+
+/// Write the hottest loops, then the source with the number of commands run
+/// on each line in the margin.  With per_instruction every command's count is
+/// listed as well.
+pub fn write_report(
+    out: &mut impl Write,
+    profile: &Profile,
+    source: &str,
+    top: usize,
+    per_instruction: bool,
+) -> io::Result<()> {
+    let total: u64 = profile.hits().iter().sum();
+    writeln!(out, "{} commands run", total)?;
+
+    let loops = profile.hottest_loops(top);
+    if !loops.is_empty() {
+        writeln!(out, "\nHottest loops:")?;
+        writeln!(
+            out,
+            "{:>12} {:>10} {:>10}  loop",
+            "commands", "entries", "iterations"
+        )?;
+        for hot_loop in loops {
+            writeln!(
+                out,
+                "{:>12} {:>10} {:>10}  {} to {}",
+                hot_loop.instructions,
+                hot_loop.entries,
+                hot_loop.iterations,
+                hot_loop.start,
+                hot_loop.end
+            )?;
+        }
+    }
+
+    if per_instruction {
+        writeln!(out, "\nCommands:")?;
+        for (instruction, hits) in profile.program().commands().iter().zip(profile.hits()) {
+            writeln!(out, "{:>12}  {}", hits, instruction)?;
+        }
+    }
+
+    writeln!(out, "\nSource:")?;
+    let line_hits = profile.line_hits();
+    for (line_number, line) in source.lines().enumerate() {
+        match line_hits.get(&line_number) {
+            Some(hits) => writeln!(out, "{:>12} | {}", hits, line)?,
+            None => writeln!(out, "{:>12} | {}", "", line)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Write how many commands ran, and list the loops which never ran
+pub fn write_coverage_summary(out: &mut impl Write, profile: &Profile) -> io::Result<()> {
+    let hits = profile.hits();
+    let never_run = profile.never_run_loops();
+
+    writeln!(
+        out,
+        "Coverage: {} of {} commands run, {} of {} loops never ran",
+        hits.iter().filter(|&&hits| hits > 0).count(),
+        hits.len(),
+        never_run.len(),
+        profile.loops().len()
+    )?;
+
+    for never_run_loop in never_run {
+        let reason = if never_run_loop.entries == 0 {
+            "never reached"
+        } else {
+            "always skipped"
+        };
+        writeln!(out, "    {} {}", never_run_loop.start, reason)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_coverage_summary, write_report};
+    use bft_interp::profile::Profile;
+    use bft_interp::BFVirtualMachine;
+    use bft_types::BFProgram;
+    use std::io::Cursor;
+
+    #[test]
+    fn report_annotates_the_source() {
+        let source = "++[>+<-]\n\ncomment >.";
+        let program = BFProgram::from_str("report.bf", source).unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        let mut profile = Profile::new(&program);
+        profile
+            .run(&mut virtual_machine, Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        let mut out = Vec::new();
+        write_report(&mut out, &profile, source, 10, true).unwrap();
+        let report = String::from_utf8(out).unwrap();
+
+        assert!(report.starts_with("15 commands run\n"));
+        assert!(report.contains(
+            "          11          1          2  [Line 0, Col 2] [ to [Line 0, Col 7] ]\n"
+        ));
+        assert!(report.contains("           2  [Line 0, Col 3] >\n"));
+        assert!(report
+            .ends_with("          13 | ++[>+<-]\n             | \n           2 | comment >.\n"));
+    }
+
+    #[test]
+    fn summary_lists_loops_which_never_ran() {
+        let program = BFProgram::from_str("summary.bf", "[[-]]+[-]\n[>]").unwrap();
+        let mut virtual_machine: BFVirtualMachine<u8> = BFVirtualMachine::new(&program, false, 4);
+        let mut profile = Profile::new(&program);
+        profile
+            .run(&mut virtual_machine, Cursor::new(vec![]), Vec::new())
+            .unwrap();
+
+        let mut out = Vec::new();
+        write_coverage_summary(&mut out, &profile).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Coverage: 6 of 12 commands run, 3 of 4 loops never ran\n    \
+             [Line 0, Col 0] [ always skipped\n    \
+             [Line 0, Col 1] [ never reached\n    \
+             [Line 1, Col 0] [ always skipped\n"
+        );
+    }
+}